@@ -2,7 +2,8 @@
 #include <stdlib.h>
 #include <string.h>
 
-extern void lexer_init(const char *lex_path, const char *src_path);
+extern void lexer_init(const char *lex_path, const char *src_path,
+                       const char *flags);
 extern size_t yylex();
 char *yytext;
 
@@ -178,7 +179,7 @@ int get_token_number(const char *token_name) {
 
 int main() {
   lexer_init("/home/zys/repo/seu_lex/c99_modified.l",
-             "/home/zys/repo/seu_lex/src.txt");
+             "/home/zys/repo/seu_lex/src.txt", NULL);
   while (1) {
     int yylex_num = yylex();
     if (yylex_num == 0) {
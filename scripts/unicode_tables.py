@@ -0,0 +1,85 @@
+# generates src/tables.rs: python3 scripts/unicode_tables.py > src/tables.rs
+#
+# the general categories come from python's unicodedata, which has to be at
+# unicode 14.0.0 (python 3.11), the alphabetic chars from `char::is_alphabetic`
+# of the rustc on the PATH, so the tables agree with the std predicates that
+# class.rs combines them with
+import os
+import subprocess
+import sys
+import tempfile
+import unicodedata
+
+if unicodedata.unidata_version != "14.0.0":
+    sys.exit(f"unicodedata is at {unicodedata.unidata_version}, not 14.0.0")
+
+# the Nd blocks of UnicodeData.txt added in unicode 15.0 to 16.0
+NEWER_DIGITS = [
+    (0x10D40, 0x10D49),
+    (0x116D0, 0x116E3),
+    (0x11BF0, 0x11BF9),
+    (0x11DE0, 0x11DE9),
+    (0x11F50, 0x11F59),
+    (0x16130, 0x16139),
+    (0x16D70, 0x16D79),
+    (0x1CCF0, 0x1CCF9),
+    (0x1E4F0, 0x1E4F9),
+    (0x1E5F1, 0x1E5FA),
+]
+
+DUMP_ALPHABETIC = """
+fn main() {
+    let chars = ('\\0'..=char::MAX).filter(|c| c.is_alphabetic());
+    for c in chars {
+        println!("{}", c as u32);
+    }
+}
+"""
+
+
+def alphabetic():
+    with tempfile.TemporaryDirectory() as dir:
+        source = os.path.join(dir, "alphabetic.rs")
+        binary = os.path.join(dir, "alphabetic")
+        with open(source, "w") as f:
+            f.write(DUMP_ALPHABETIC)
+        subprocess.run(["rustc", "-O", "-o", binary, source], check=True)
+        output = subprocess.run([binary], check=True, capture_output=True, text=True)
+        return [int(line) for line in output.stdout.split()]
+
+
+def runs(chars):
+    ranges = []
+    for c in chars:
+        if ranges and ranges[-1][1] == c - 1:
+            ranges[-1][1] = c
+        else:
+            ranges.append([c, c])
+    return ranges
+
+
+def table(ranges):
+    return "\n".join("    ('\\u{%x}', '\\u{%x}')," % (lo, hi) for lo, hi in ranges)
+
+
+# categories L* are letters, C* includes the chars unassigned in 14.0.0
+non_letters = runs(c for c in alphabetic() if unicodedata.category(chr(c))[0] not in "LC")
+digits = {c for c in range(0x110000) if unicodedata.category(chr(c)) == "Nd"}
+for lo, hi in NEWER_DIGITS:
+    digits.update(range(lo, hi + 1))
+
+print(f"""\
+// tables for the \\p{{...}} classes that the std `char` predicates can't
+// derive, generated by scripts/unicode_tables.py from the general categories
+// of UnicodeData.txt, version 14.0.0 plus the decimal digits added since
+
+// the alphabetic chars that aren't letters: marks, letter numbers and the
+// circled letters, chars `is_alphabetic` added after version 14 count as letters
+pub const ALPHABETIC_NON_LETTERS: &[(char, char)] = &[
+{table(non_letters)}
+];
+
+// general category Nd, every range is one or more runs of the digits 0 to 9
+pub const DECIMAL_DIGITS: &[(char, char)] = &[
+{table(runs(sorted(digits)))}
+];""")
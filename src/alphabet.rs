@@ -0,0 +1,146 @@
+// splits all chars into equivalence classes, chars of the same class go to the
+// same state from every state of a DFA, so a transition table needs one column
+// per class instead of one per char
+#[derive(Debug, Clone)]
+pub struct Alphabet {
+    ascii: [u32; 128],
+    // the first char of every interval of equal chars, sorted, and its class
+    starts: Vec<u32>,
+    classes: Vec<u32>,
+    // a char of every class
+    representatives: Vec<char>,
+}
+
+impl Default for Alphabet {
+    // a single class holding every char
+    fn default() -> Self {
+        Alphabet {
+            ascii: [0; 128],
+            starts: vec![0],
+            classes: vec![0],
+            representatives: vec!['\0'],
+        }
+    }
+}
+
+impl Alphabet {
+    // `ranges` are the transitions of every state, `next` tells where a state
+    // goes on a char, chars that no range separates always share a class
+    pub fn new(
+        states: usize,
+        ranges: impl Iterator<Item = (char, char)>,
+        next: impl Fn(usize, char) -> Option<usize>,
+    ) -> Alphabet {
+        Alphabet::with_columns(ranges, |c| {
+            (0..states).map(|s| next(s, c)).collect::<Vec<_>>()
+        })
+    }
+    // a class for every interval between the ends of `ranges`, for automata
+    // whose states aren't all known up front
+    pub fn intervals(ranges: impl Iterator<Item = (char, char)>) -> Alphabet {
+        Alphabet::with_columns(ranges, |c| c)
+    }
+    // intervals whose chars have the same `column` get the same class
+    fn with_columns<K: std::hash::Hash + Eq>(
+        ranges: impl Iterator<Item = (char, char)>,
+        column: impl Fn(char) -> K,
+    ) -> Alphabet {
+        let mut points = vec![0];
+        for (lo, hi) in ranges {
+            points.push(lo as u32);
+            points.push(hi as u32 + 1);
+        }
+        points.sort();
+        points.dedup();
+        points.retain(|&p| p <= char::MAX as u32);
+        let mut alphabet = Alphabet {
+            ascii: [0; 128],
+            starts: Vec::new(),
+            classes: Vec::new(),
+            representatives: Vec::new(),
+        };
+        let mut columns = std::collections::HashMap::new();
+        for (i, &start) in points.iter().enumerate() {
+            let end = points.get(i + 1).copied().unwrap_or(char::MAX as u32 + 1);
+            // an interval starting in the surrogate gap holds chars from its end on
+            let Some(c) = char::from_u32(start).or_else(|| (end > 0xe000).then_some('\u{e000}'))
+            else {
+                continue;
+            };
+            let class = *columns.entry(column(c)).or_insert_with(|| {
+                alphabet.representatives.push(c);
+                alphabet.representatives.len() as u32 - 1
+            });
+            alphabet.starts.push(start);
+            alphabet.classes.push(class);
+        }
+        for c in 0..128u8 {
+            alphabet.ascii[c as usize] = alphabet.lookup(c as char);
+        }
+        alphabet
+    }
+    fn lookup(&self, c: char) -> u32 {
+        let i = self.starts.partition_point(|&start| start <= c as u32);
+        self.classes[i - 1]
+    }
+    pub fn class(&self, c: char) -> usize {
+        match self.ascii.get(c as usize) {
+            Some(&class) => class as usize,
+            None => self.lookup(c) as usize,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.representatives.len()
+    }
+    pub fn representatives(&self) -> &[char] {
+        &self.representatives
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classes() {
+        // state 0 goes to 1 on [a-z] and to 2 on [0-9], state 1 goes to 1 on
+        // [a-z0-9], so letters, digits and everything else are the classes
+        let transitions = [
+            vec![('0', '9', 2), ('a', 'z', 1)],
+            vec![('0', '9', 1), ('a', 'z', 1)],
+            vec![],
+        ];
+        let next = |state: usize, c: char| {
+            transitions[state]
+                .iter()
+                .find(|&&(lo, hi, _)| lo <= c && c <= hi)
+                .map(|&(_, _, next)| next)
+        };
+        let ranges = transitions.iter().flatten().map(|&(lo, hi, _)| (lo, hi));
+        let alphabet = Alphabet::new(3, ranges, next);
+        assert_eq!(alphabet.len(), 3);
+        assert_eq!(alphabet.class('a'), alphabet.class('z'));
+        assert_eq!(alphabet.class('0'), alphabet.class('9'));
+        assert_ne!(alphabet.class('a'), alphabet.class('0'));
+        assert_eq!(alphabet.class(' '), alphabet.class('正'));
+        assert_eq!(alphabet.class('{'), alphabet.class(char::MAX));
+        for &c in alphabet.representatives() {
+            assert_eq!(
+                alphabet.representatives()[alphabet.class(c)],
+                c,
+                "{:?} represents its own class",
+                c
+            );
+        }
+        let ranges = transitions.iter().flatten().map(|&(lo, hi, _)| (lo, hi));
+        let intervals = Alphabet::intervals(ranges);
+        // digits, letters and the three gaps around them
+        assert_eq!(intervals.len(), 5);
+        assert_ne!(intervals.class(' '), intervals.class('{'));
+        let single = Alphabet::default();
+        assert_eq!(
+            (single.len(), single.class('x'), single.class('正')),
+            (1, 0, 0)
+        );
+    }
+}
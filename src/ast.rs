@@ -1,270 +1,809 @@
-use display_tree::{AsTree, CharSet, DisplayTree, StyleBuilder};
+use crate::class::CharClass;
+use crate::common::Definition;
+use display_tree::DisplayTree;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
 
 #[derive(DisplayTree, Debug, Clone)]
 pub enum AstNode {
     And(#[tree] Box<AstNode>, #[tree] Box<AstNode>),
     Or(#[tree] Box<AstNode>, #[tree] Box<AstNode>),
+    Intersect(#[tree] Box<AstNode>, #[tree] Box<AstNode>),
+    Complement(#[tree] Box<AstNode>),
     Star(#[tree] Box<AstNode>),
     Plus(#[tree] Box<AstNode>),
     Question(#[tree] Box<AstNode>),
+    IgnoreCase(#[tree] Box<AstNode>),
     Char(char),
-    Span(char, char),
+    Class(CharClass),
 }
 
-pub struct Parser {
-    pattern: String,
+// where the trailing context of `r/s` begins, taken from whichever side has a fixed length
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrailingContext {
+    // the token is the first n chars of the match
+    Head(usize),
+    // the last n chars of the match are given back
+    Trail(usize),
 }
 
-#[derive(Eq, PartialEq, Debug)]
-enum CharType {
-    End,
-    Begin,
-    Middle,
-    SpanMiddle,
-    BackSlash,
-    Normal,
+// a whole rule pattern, `^` and `$` are only anchors at its start and end
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    pub ast: AstNode,
+    pub trailing: Option<(AstNode, TrailingContext)>,
+    pub bol: bool,
+    pub eol: bool,
 }
 
-impl Parser {
-    pub fn new(pattern: String) -> Self {
-        Parser { pattern }
-    }
-    fn add_dot(&mut self) {
-        let check_char = |c: char| -> CharType {
-            match c {
-                '(' | '[' => CharType::Begin,
-                ')' | ']' => CharType::End,
-                '*' | '+' | '?' => CharType::End,
-                '|' => CharType::Middle,
-                '-' => CharType::SpanMiddle,
-                _ => CharType::Normal,
-            }
-        };
+impl From<AstNode> for Pattern {
+    fn from(ast: AstNode) -> Self {
+        Pattern {
+            ast,
+            trailing: None,
+            bol: false,
+            eol: false,
+        }
+    }
+}
 
-        let mut new_pattern = String::new();
-        let mut chars = self.pattern.chars();
-        let mut prev = chars.next().unwrap();
-        new_pattern.push(prev);
-        for c in chars {
-            let prev_type = check_char(prev);
-            let cur_type = check_char(c);
-            if prev_type == CharType::SpanMiddle || cur_type == CharType::SpanMiddle {
-            } else if prev_type == CharType::Normal
-                && (cur_type == CharType::Normal || cur_type == CharType::Begin)
-            {
-                new_pattern.push('.');
-            } else if prev_type == CharType::End
-                && (cur_type == CharType::Begin || cur_type == CharType::Normal)
-            {
-                new_pattern.push('.');
+impl AstNode {
+    // whether the node matches the empty string
+    pub fn nullable(&self) -> bool {
+        match self {
+            AstNode::And(left, right) | AstNode::Intersect(left, right) => {
+                left.nullable() && right.nullable()
             }
-            new_pattern.push(c);
-            prev = c;
+            AstNode::Or(left, right) => left.nullable() || right.nullable(),
+            AstNode::Complement(node) => !node.nullable(),
+            AstNode::Plus(node) | AstNode::IgnoreCase(node) => node.nullable(),
+            AstNode::Star(_) | AstNode::Question(_) => true,
+            AstNode::Char(_) | AstNode::Class(_) => false,
         }
-        self.pattern = new_pattern;
-    }
-    fn to_postfix(&mut self) -> String {
-        let mut postfix = String::new();
-        let mut op_stack: Vec<char> = Vec::new();
-        let precedence = |c: char| -> i32 {
-            match c {
-                '*' => 3,
-                '.' => 2,
-                '|' => 1,
-                _ => 0,
-            }
-        };
-        for c in self.pattern.chars() {
-            match c {
-                '(' => op_stack.push(c),
-                ')' => {
-                    while let Some(op) = op_stack.pop() {
-                        if op == '(' {
-                            break;
-                        }
-                        postfix.push(op);
-                    }
-                }
-                '*' | '.' | '|' => {
-                    while let Some(op) = op_stack.last() {
-                        if precedence(c) <= precedence(*op) {
-                            postfix.push(op_stack.pop().unwrap());
-                        } else {
-                            break;
-                        }
-                    }
-                    op_stack.push(c);
-                }
-                _ => postfix.push(c),
+    }
+    // the length of every string the node matches, if they all have the same one
+    pub fn fixed_length(&self) -> Option<usize> {
+        match self {
+            AstNode::And(left, right) => Some(left.fixed_length()? + right.fixed_length()?),
+            AstNode::Or(left, right) => {
+                let len = left.fixed_length()?;
+                (right.fixed_length()? == len).then_some(len)
             }
+            AstNode::Intersect(left, right) => left.fixed_length().or(right.fixed_length()),
+            AstNode::Complement(_) | AstNode::Star(_) | AstNode::Question(_) => None,
+            AstNode::Plus(node) => (node.fixed_length()? == 0).then_some(0),
+            AstNode::IgnoreCase(node) => node.fixed_length(),
+            AstNode::Char(_) | AstNode::Class(_) => Some(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub pos: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.pos)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn is_definition_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+// what a backslash escape stands for
+enum Escape {
+    Char(char),
+    Class(CharClass),
+}
+
+// the patterns `{NAME}` refers to, each is parsed the first time it's used
+pub struct Definitions {
+    patterns: HashMap<String, String>,
+    parsed: RefCell<HashMap<String, AstNode>>,
+    // the definitions being parsed right now, to catch recursion
+    stack: RefCell<Vec<String>>,
+}
+
+impl Definitions {
+    pub fn new(definitions: &[Definition]) -> Self {
+        Definitions {
+            patterns: definitions
+                .iter()
+                .map(|d| (d.name.clone(), d.pattern.clone()))
+                .collect(),
+            parsed: RefCell::new(HashMap::new()),
+            stack: RefCell::new(Vec::new()),
         }
-        while let Some(op) = op_stack.pop() {
-            postfix.push(op);
+    }
+    pub fn get(&self, name: &str) -> Result<AstNode, String> {
+        if let Some(node) = self.parsed.borrow().get(name) {
+            return Ok(node.clone());
         }
-        postfix
-    }
-    fn to_ast(&mut self) -> AstNode {
-        let postfix = self.to_postfix();
-        let mut stack: Vec<Box<AstNode>> = Vec::new();
-        for c in postfix.chars() {
-            match c {
-                '.' => {
-                    let right = stack.pop().unwrap();
-                    let left = stack.pop().unwrap();
-                    stack.push(Box::new(AstNode::And(left, right)));
-                }
-                '|' => {
-                    let right = stack.pop().unwrap();
-                    let left = stack.pop().unwrap();
-                    stack.push(Box::new(AstNode::Or(left, right)));
-                }
-                '*' => {
-                    let node = stack.pop().unwrap();
-                    stack.push(Box::new(AstNode::Star(node)));
-                }
-                _ => {
-                    stack.push(Box::new(AstNode::Char(c)));
-                }
-            }
+        let pattern = self
+            .patterns
+            .get(name)
+            .ok_or_else(|| format!("undefined definition {{{}}}", name))?;
+        if self.stack.borrow().iter().any(|n| n == name) {
+            return Err(format!("{{{}}} refers to itself", name));
+        }
+        self.stack.borrow_mut().push(name.to_string());
+        let node = Parser::new(pattern.clone()).with_definitions(self).parse();
+        self.stack.borrow_mut().pop();
+        let node = node.map_err(|e| format!("in {{{}}}: {}", name, e.message))?;
+        self.parsed
+            .borrow_mut()
+            .insert(name.to_string(), node.clone());
+        Ok(node)
+    }
+}
+
+pub struct Parser<'a> {
+    pattern: Vec<char>,
+    pos: usize,
+    // a trailing unescaped `$` is an anchor rather than a char
+    eol: bool,
+    definitions: Option<&'a Definitions>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(pattern: String) -> Self {
+        Parser {
+            pattern: pattern.chars().collect(),
+            pos: 0,
+            eol: false,
+            definitions: None,
+        }
+    }
+    pub fn with_definitions(mut self, definitions: &'a Definitions) -> Self {
+        self.definitions = Some(definitions);
+        self
+    }
+    fn peek(&self) -> Option<char> {
+        self.pattern.get(self.pos).copied()
+    }
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        if c.is_some() {
+            self.pos += 1;
         }
-        *stack.pop().unwrap()
-    }
-    fn to_ast_directly(&mut self) -> AstNode {
-        let mut stack: Vec<Box<AstNode>> = Vec::new();
-        let mut op_stack: Vec<char> = Vec::new();
-        let mut span_stack: Vec<char> = Vec::new();
-        let precedence = |c: char| -> i32 {
-            match c {
-                '*' => 3,
-                '+' => 3,
-                '?' => 3,
-                '.' => 2,
-                '|' => 1,
-                _ => 0,
+        c
+    }
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            pos: self.pos,
+            message: message.into(),
+        })
+    }
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => {
+                self.pos -= 1;
+                self.error(format!("expected '{}', found '{}'", expected, c))
             }
-        };
+            None => self.error(format!("expected '{}', found end of pattern", expected)),
+        }
+    }
 
-        let stack_push = |op: char, stack: &mut Vec<Box<AstNode>>| match op {
-            '.' => {
-                let right = stack.pop().unwrap();
-                let left = stack.pop().unwrap();
-                stack.push(Box::new(AstNode::And(left, right)));
+    fn parse_or(&mut self) -> Result<AstNode, ParseError> {
+        let mut node = self.parse_intersect()?;
+        while self.peek() == Some('|') {
+            self.next();
+            let right = self.parse_intersect()?;
+            node = AstNode::Or(Box::new(node), Box::new(right));
+        }
+        Ok(node)
+    }
+    fn parse_intersect(&mut self) -> Result<AstNode, ParseError> {
+        let mut node = self.parse_and()?;
+        while self.peek() == Some('&') {
+            self.next();
+            let right = self.parse_and()?;
+            node = AstNode::Intersect(Box::new(node), Box::new(right));
+        }
+        Ok(node)
+    }
+    fn parse_and(&mut self) -> Result<AstNode, ParseError> {
+        let mut node: Option<AstNode> = None;
+        while let Some(c) = self.peek() {
+            if c == '|' || c == '&' || c == ')' || c == '/' {
+                break;
             }
-            '|' => {
-                let right = stack.pop().unwrap();
-                let left = stack.pop().unwrap();
-                stack.push(Box::new(AstNode::Or(left, right)));
+            if c == '$' && self.eol && self.pos + 1 == self.pattern.len() {
+                break;
             }
-            '*' => {
-                let node = stack.pop().unwrap();
-                stack.push(Box::new(AstNode::Star(node)));
+            let right = self.parse_complement()?;
+            node = Some(match node {
+                Some(left) => AstNode::And(Box::new(left), Box::new(right)),
+                None => right,
+            });
+        }
+        match node {
+            Some(node) => Ok(node),
+            None => self.error("empty expression"),
+        }
+    }
+    fn parse_complement(&mut self) -> Result<AstNode, ParseError> {
+        if self.peek() == Some('~') {
+            self.next();
+            let node = self.parse_complement()?;
+            return Ok(AstNode::Complement(Box::new(node)));
+        }
+        self.parse_repeat()
+    }
+    fn parse_repeat(&mut self) -> Result<AstNode, ParseError> {
+        let mut node = self.parse_atom()?;
+        while let Some(c) = self.peek() {
+            if c == '{' {
+                self.check_counts()?;
+                break;
             }
-            '+' => {
-                let node = stack.pop().unwrap();
-                stack.push(Box::new(AstNode::Plus(node)));
+            node = match c {
+                '*' => AstNode::Star(Box::new(node)),
+                '+' => AstNode::Plus(Box::new(node)),
+                '?' => AstNode::Question(Box::new(node)),
+                _ => break,
+            };
+            self.next();
+        }
+        Ok(node)
+    }
+    // flex's `{n}`, `{n,}` and `{n,m}` aren't supported, they are an error
+    // rather than chars so they can't silently match something else
+    fn check_counts(&self) -> Result<(), ParseError> {
+        let Some(len) = self.pattern[self.pos..].iter().position(|&c| c == '}') else {
+            return Ok(());
+        };
+        let counts = self.pattern[self.pos + 1..self.pos + len]
+            .iter()
+            .collect::<String>();
+        let (min, max) = counts.split_once(',').unwrap_or((&counts, ""));
+        let is_count = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+        match !min.is_empty() && is_count(min) && is_count(max) {
+            true => self.error(format!(
+                "repetition counts {{{}}} are not supported",
+                counts
+            )),
+            false => Ok(()),
+        }
+    }
+    fn parse_atom(&mut self) -> Result<AstNode, ParseError> {
+        let start = self.pos;
+        match self.next() {
+            Some('(') if self.peek() == Some('?') => {
+                self.next();
+                if self.next() != Some('i') || self.next() != Some(':') {
+                    self.pos = start;
+                    return self.error("unknown group flags, expected '(?i:'");
+                }
+                let node = self.parse_or()?;
+                self.expect(')')?;
+                Ok(AstNode::IgnoreCase(Box::new(node)))
             }
-            '?' => {
-                let node = stack.pop().unwrap();
-                stack.push(Box::new(AstNode::Question(node)));
+            Some('(') => {
+                let node = self.parse_or()?;
+                self.expect(')')?;
+                Ok(node)
             }
-            _ => {}
-        };
-
-        for c in self.pattern.chars() {
-            match c {
-                '(' => op_stack.push(c),
-                ')' => {
-                    while let Some(op) = op_stack.pop() {
-                        if op == '(' {
-                            break;
-                        }
-                        stack_push(op, &mut stack);
-                    }
+            Some('[') => self.parse_class(),
+            Some('{') => match self.parse_reference() {
+                Some(name) => {
+                    let node = match self.definitions {
+                        Some(definitions) => definitions.get(&name),
+                        None => Err(format!("undefined definition {{{}}}", name)),
+                    };
+                    node.or_else(|message| {
+                        self.pos = start;
+                        self.error(message)
+                    })
                 }
-                '[' => {
-                    span_stack.push(c);
+                None => {
+                    self.pos = start;
+                    self.check_counts()?;
+                    self.pos = start + 1;
+                    Ok(AstNode::Char('{'))
                 }
-                '-' => {}
-                ']' => {
-                    while span_stack.last().unwrap() != &'[' {
-                        let right = span_stack.pop().unwrap();
-                        let left = span_stack.pop().unwrap();
-                        stack.push(Box::new(AstNode::Span(left, right)));
+            },
+            Some('"') => self.parse_quoted(),
+            Some('.') => Ok(AstNode::Class(CharClass::any_but_newline())),
+            Some('\\') => match self.parse_escape()? {
+                Escape::Char(c) => Ok(AstNode::Char(c)),
+                Escape::Class(class) => Ok(AstNode::Class(class)),
+            },
+            Some(c @ ('*' | '+' | '?')) => {
+                self.pos = start;
+                self.error(format!("'{}' has nothing to repeat", c))
+            }
+            Some(']') => {
+                self.pos = start;
+                self.error("unmatched ']'")
+            }
+            Some(c) => Ok(AstNode::Char(c)),
+            None => self.error("unexpected end of pattern"),
+        }
+    }
+    // the name of `{NAME}`, called after the brace has been consumed,
+    // a brace not followed by a name and `}` is a plain char
+    fn parse_reference(&mut self) -> Option<String> {
+        let len = self.pattern[self.pos..].iter().position(|&c| c == '}')?;
+        let name = self.pattern[self.pos..self.pos + len]
+            .iter()
+            .collect::<String>();
+        if !is_definition_name(&name) {
+            return None;
+        }
+        self.pos += len + 1;
+        Some(name)
+    }
+    fn parse_quoted(&mut self) -> Result<AstNode, ParseError> {
+        let mut node: Option<AstNode> = None;
+        loop {
+            let c = match self.next() {
+                Some('"') => break,
+                Some('\\') => match self.parse_escape()? {
+                    Escape::Char(c) => c,
+                    Escape::Class(_) => {
+                        return self.error("character classes are not allowed in quotes")
                     }
-                    span_stack.clear();
-                }
-                '*' | '+' | '?' | '.' | '|' => {
-                    while let Some(op) = op_stack.last() {
-                        if precedence(c) <= precedence(*op) {
-                            stack_push(op_stack.pop().unwrap(), &mut stack);
-                        } else {
-                            break;
-                        }
+                },
+                Some(c) => c,
+                None => return self.error("unterminated quote"),
+            };
+            node = Some(match node {
+                Some(left) => AstNode::And(Box::new(left), Box::new(AstNode::Char(c))),
+                None => AstNode::Char(c),
+            });
+        }
+        match node {
+            Some(node) => Ok(node),
+            None => self.error("empty quoted string"),
+        }
+    }
+    fn parse_class(&mut self) -> Result<AstNode, ParseError> {
+        let mut class = CharClass::new();
+        // `[^...]` matches every char not listed, like in flex
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.next();
+        }
+        loop {
+            let lo = match self.next() {
+                Some(']') => break,
+                Some('\\') => match self.parse_escape()? {
+                    Escape::Char(c) => c,
+                    Escape::Class(other) => {
+                        class.union(&other);
+                        continue;
                     }
-                    op_stack.push(c);
-                }
-                _ => {
-                    if span_stack.is_empty() {
-                        stack.push(Box::new(AstNode::Char(c)));
-                    } else {
-                        span_stack.push(c);
+                },
+                Some(c) => c,
+                None => return self.error("unterminated character class"),
+            };
+            let is_range = self.peek() == Some('-')
+                && !matches!(self.pattern.get(self.pos + 1), None | Some(']'));
+            if !is_range {
+                class.push(lo, lo);
+                continue;
+            }
+            self.next();
+            let hi = match self.next() {
+                Some('\\') => match self.parse_escape()? {
+                    Escape::Char(c) => c,
+                    Escape::Class(_) => return self.error("invalid range end"),
+                },
+                Some(c) => c,
+                None => return self.error("unterminated character class"),
+            };
+            if lo > hi {
+                return self.error(format!("invalid range '{}-{}'", lo, hi));
+            }
+            class.push(lo, hi);
+        }
+        if class.is_empty() {
+            return self.error("empty character class");
+        }
+        if negated {
+            class = class.negate();
+        }
+        Ok(AstNode::Class(class))
+    }
+    // called after the backslash has been consumed
+    fn parse_escape(&mut self) -> Result<Escape, ParseError> {
+        let c = match self.next() {
+            Some(c) => c,
+            None => return self.error("unterminated escape sequence"),
+        };
+        let escape = match c {
+            'n' => Escape::Char('\n'),
+            't' => Escape::Char('\t'),
+            'r' => Escape::Char('\r'),
+            'f' => Escape::Char('\u{0c}'),
+            'v' => Escape::Char('\u{0b}'),
+            '0' => Escape::Char('\0'),
+            'x' => Escape::Char(self.parse_hex(Some(2))?),
+            'u' => {
+                self.expect('{')?;
+                let c = self.parse_hex(None)?;
+                self.expect('}')?;
+                Escape::Char(c)
+            }
+            'd' => Escape::Class(CharClass::digit()),
+            'D' => Escape::Class(CharClass::digit().negate()),
+            'w' => Escape::Class(CharClass::word()),
+            'W' => Escape::Class(CharClass::word().negate()),
+            's' => Escape::Class(CharClass::space()),
+            'S' => Escape::Class(CharClass::space().negate()),
+            'p' | 'P' => {
+                let start = self.pos;
+                self.expect('{')?;
+                let len = match self.pattern[self.pos..].iter().position(|&c| c == '}') {
+                    Some(len) => len,
+                    None => return self.error("unterminated property name"),
+                };
+                let name: String = self.pattern[self.pos..self.pos + len].iter().collect();
+                let class = match CharClass::property(&name) {
+                    Some(class) => class,
+                    None => {
+                        self.pos = start;
+                        return self.error(format!("unknown property '{}'", name));
                     }
+                };
+                self.pos += len + 1;
+                if c == 'P' {
+                    Escape::Class(class.negate())
+                } else {
+                    Escape::Class(class)
                 }
             }
+            c if c.is_ascii_alphanumeric() => {
+                self.pos -= 1;
+                return self.error(format!("unknown escape sequence '\\{}'", c));
+            }
+            c => Escape::Char(c),
+        };
+        Ok(escape)
+    }
+    // exactly `digits` hex digits, or 1 to 6 of them when `digits` is None
+    fn parse_hex(&mut self, digits: Option<usize>) -> Result<char, ParseError> {
+        let start = self.pos;
+        let max = digits.unwrap_or(6);
+        while self.pos - start < max && self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+            self.pos += 1;
         }
-        while let Some(op) = op_stack.pop() {
-            stack_push(op, &mut stack);
+        let len = self.pos - start;
+        if len == 0 || digits.is_some_and(|d| d != len) {
+            return self.error("invalid hex escape");
+        }
+        let hex: String = self.pattern[start..self.pos].iter().collect();
+        let value = u32::from_str_radix(&hex, 16).unwrap();
+        match char::from_u32(value) {
+            Some(c) => Ok(c),
+            None => {
+                self.pos = start;
+                self.error(format!("invalid code point {:#x}", value))
+            }
         }
-        *stack.pop().unwrap()
     }
-    pub fn parse(&mut self) -> AstNode {
-        self.add_dot();
-        self.to_ast_directly()
+    fn finish(&self) -> Result<(), ParseError> {
+        match self.peek() {
+            None => Ok(()),
+            Some(')') => self.error("unmatched ')'"),
+            Some(c) => self.error(format!("unexpected '{}'", c)),
+        }
+    }
+    // parses a whole rule: `^`, then `r`, `r/s` or `r$`
+    pub fn parse_pattern(&mut self) -> Result<Pattern, ParseError> {
+        let bol = self.peek() == Some('^');
+        if bol {
+            self.next();
+        }
+        self.eol = true;
+        let ast = self.parse_or()?;
+        let mut trailing = None;
+        if self.peek() == Some('/') {
+            let slash = self.pos;
+            self.next();
+            let trail = self.parse_or()?;
+            // an empty match of `r` would make an empty token that never moves on
+            if ast.nullable() {
+                self.pos = slash;
+                return self.error(
+                    "ambiguous trailing context, the part before '/' can match the empty string",
+                );
+            }
+            let context = match (ast.fixed_length(), trail.fixed_length()) {
+                (Some(len), _) => TrailingContext::Head(len),
+                (None, Some(len)) => TrailingContext::Trail(len),
+                (None, None) => {
+                    self.pos = slash;
+                    return self.error(
+                        "ambiguous trailing context, either side of '/' must have a fixed length",
+                    );
+                }
+            };
+            trailing = Some((trail, context));
+        }
+        let eol = self.peek() == Some('$') && self.pos + 1 == self.pattern.len();
+        if eol {
+            if trailing.is_some() {
+                return self.error("'$' can't be combined with trailing context");
+            }
+            self.next();
+        }
+        self.finish()?;
+        Ok(Pattern {
+            ast,
+            trailing,
+            bol,
+            eol,
+        })
+    }
+    #[allow(dead_code)]
+    pub fn parse(&mut self) -> Result<AstNode, ParseError> {
+        let node = self.parse_or()?;
+        self.finish()?;
+        Ok(node)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use display_tree::{AsTree, CharSet, StyleBuilder};
 
-    #[test]
-    fn test_add_dot() {
-        let mut parser = Parser::new("(a|b)*abb".to_string());
-        parser.add_dot();
-        println!("{}", parser.pattern);
-    }
-
-    #[test]
-    fn test_to_postfix() {
-        let mut parser = Parser::new("(a_b|a*b)*".to_string());
-        parser.add_dot();
-        let postfix = parser.to_postfix();
-        println!("{}", postfix);
+    fn parse(pattern: &str) -> Result<AstNode, ParseError> {
+        Parser::new(pattern.to_string()).parse()
     }
 
     #[test]
-    fn test_to_ast() {
-        let mut parser = Parser::new("(a|b)*abb".to_string());
-        parser.add_dot();
-        let ast = parser.to_ast();
+    fn test_parse() {
+        let ast = parse("(a|b)*abb").unwrap();
         println!(
             "{}",
             AsTree::new(&ast)
                 .indentation(1)
                 .char_set(CharSet::DOUBLE_LINE)
         );
+        assert_eq!(
+            format!("{:?}", ast),
+            format!("{:?}", parse("((((a|b)*)a)b)b").unwrap())
+        );
     }
 
     #[test]
-    fn test_to_ast_directly() {
-        let mut parser = Parser::new("(a|b)*abb".to_string());
-        parser.add_dot();
-        let ast = parser.to_ast_directly();
-        println!(
-            "{}",
-            AsTree::new(&ast)
-                .indentation(1)
-                .char_set(CharSet::DOUBLE_LINE)
+    fn test_parse_precedence() {
+        let ast = parse("a_b|a*b").unwrap();
+        assert_eq!(
+            format!("{:?}", ast),
+            format!("{:?}", parse("((a_)b)|((a*)b)").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_escapes() {
+        let chars = |pattern: &str| match parse(pattern).unwrap() {
+            AstNode::Char(c) => c,
+            ast => panic!("expected a char, got {:?}", ast),
+        };
+        assert_eq!(chars("\\n"), '\n');
+        assert_eq!(chars("\\0"), '\0');
+        assert_eq!(chars("\\f"), '\u{0c}');
+        assert_eq!(chars("\\v"), '\u{0b}');
+        assert_eq!(chars("\\x41"), 'A');
+        assert_eq!(chars("\\u{6b63}"), '正');
+        assert_eq!(chars("\\u{10FFFF}"), '\u{10ffff}');
+        assert_eq!(chars("\\|"), '|');
+        assert_eq!(chars("\\\""), '"');
+        assert!(parse("\\x4").is_err());
+        assert!(parse("\\u{110000}").is_err());
+        assert!(parse("\\u{d800}").is_err());
+        assert!(parse("\\q").is_err());
+    }
+
+    #[test]
+    fn test_parse_classes() {
+        let class = |pattern: &str| match parse(pattern).unwrap() {
+            AstNode::Class(class) => class,
+            ast => panic!("expected a class, got {:?}", ast),
+        };
+        assert_eq!(class("\\d"), CharClass::digit());
+        assert_eq!(class("[\\d]"), CharClass::digit());
+        assert_eq!(class("\\S"), CharClass::space().negate());
+        assert_eq!(
+            class("[a-zA-Z_]").ranges(),
+            &[('A', 'Z'), ('_', '_'), ('a', 'z')]
+        );
+        assert_eq!(
+            class("[\\d\\x41-\\x46-]").ranges(),
+            &[('-', '-'), ('0', '9'), ('A', 'F')]
+        );
+        assert_eq!(class("[\\]\\\\]").ranges(), &[('\\', ']')]);
+        assert_eq!(
+            class("\\P{Alphabetic}"),
+            CharClass::property("Alphabetic").unwrap().negate()
+        );
+        assert_eq!(class("[\\p{ascii}\\p{Any}]"), CharClass::new().negate());
+        assert_eq!(class("[^\\d]"), CharClass::digit().negate());
+        assert_eq!(class("[a^]").ranges(), &[('^', '^'), ('a', 'a')]);
+        assert!(parse("\\p{Nope}").is_err());
+        assert!(parse("\\p{Alphabetic").is_err());
+        assert!(parse("[z-a]").is_err());
+        assert!(parse("[a-\\d]").is_err());
+        assert!(parse("[]").is_err());
+        assert!(parse("[abc").is_err());
+    }
+
+    #[test]
+    fn test_parse_quoted() {
+        let ast = parse("\"a|\\\"\\n\"").unwrap();
+        assert_eq!(
+            format!("{:?}", ast),
+            format!("{:?}", parse("a\\|\\\"\\n").unwrap())
+        );
+        assert!(parse("\"abc").is_err());
+        assert!(parse("\"\\d\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_ignore_case() {
+        let ast = parse("a(?i:b|c)+").unwrap();
+        assert_eq!(
+            format!("{:?}", ast),
+            "And(Char('a'), Plus(IgnoreCase(Or(Char('b'), Char('c')))))"
+        );
+        assert!(parse("(?x:a)").is_err());
+        assert!(parse("(?i:a").is_err());
+    }
+
+    #[test]
+    fn test_parse_complement_intersect() {
+        let ast = parse("a|~b*c&d").unwrap();
+        assert_eq!(
+            format!("{:?}", ast),
+            "Or(Char('a'), Intersect(And(Complement(Star(Char('b'))), Char('c')), Char('d')))"
+        );
+        assert_eq!(
+            format!("{:?}", parse("\\~\\&").unwrap()),
+            "And(Char('~'), Char('&'))"
+        );
+        assert!(parse("a&").is_err());
+        assert!(parse("~").is_err());
+    }
+
+    #[test]
+    fn test_parse_anchors() {
+        let pattern = |s: &str| {
+            let p = Parser::new(s.to_string()).parse_pattern().unwrap();
+            (format!("{:?}", p.ast), p.bol, p.eol)
+        };
+        assert_eq!(
+            pattern("^ab$"),
+            ("And(Char('a'), Char('b'))".to_string(), true, true)
+        );
+        assert_eq!(
+            pattern("a|b$"),
+            ("Or(Char('a'), Char('b'))".to_string(), false, true)
+        );
+        assert_eq!(
+            pattern("\\^a\\$"),
+            (
+                "And(And(Char('^'), Char('a')), Char('$'))".to_string(),
+                false,
+                false
+            )
+        );
+        assert_eq!(
+            pattern("a^$b"),
+            (
+                "And(And(And(Char('a'), Char('^')), Char('$')), Char('b'))".to_string(),
+                false,
+                false
+            )
+        );
+        assert_eq!(pattern("\"$\""), ("Char('$')".to_string(), false, false));
+        assert!(Parser::new("^$".to_string()).parse_pattern().is_err());
+    }
+
+    #[test]
+    fn test_parse_trailing_context() {
+        let trailing = |s: &str| {
+            Parser::new(s.to_string())
+                .parse_pattern()
+                .map(|p| p.trailing.map(|(_, context)| context))
+        };
+        assert_eq!(trailing("[0-9]+"), Ok(None));
+        assert_eq!(
+            trailing("DO/[A-Z0-9]*=[A-Z0-9]*,"),
+            Ok(Some(TrailingContext::Head(2)))
+        );
+        assert_eq!(
+            trailing("[0-9]+/\"..\""),
+            Ok(Some(TrailingContext::Trail(2)))
+        );
+        assert_eq!(
+            trailing("(ab|cd)/(?i:x)[0-9]"),
+            Ok(Some(TrailingContext::Head(2)))
         );
+        assert_eq!(trailing("a+/b+").unwrap_err().pos, 2);
+        assert_eq!(trailing("a*/b").unwrap_err().pos, 2);
+        assert_eq!(trailing("(a|~b)/b").unwrap_err().pos, 6);
+        assert!(trailing("a/b$").is_err());
+        assert!(trailing("a/b/c").is_err());
+        assert!(trailing("(a/b)").is_err());
+        assert!(parse("a/b").is_err());
+        assert!(parse("a\\/b").is_ok());
+    }
+
+    #[test]
+    fn test_parse_definitions() {
+        let define = |definitions: &[(&str, &str)]| {
+            let definitions = definitions
+                .iter()
+                .map(|(name, pattern)| Definition {
+                    name: name.to_string(),
+                    pattern: pattern.to_string(),
+                    line: None,
+                })
+                .collect::<Vec<_>>();
+            Definitions::new(&definitions)
+        };
+        let definitions = define(&[("AB", "a|b"), ("ABC", "{AB}c"), ("D", "[0-9]")]);
+        let parse = |pattern: &str| {
+            Parser::new(pattern.to_string())
+                .with_definitions(&definitions)
+                .parse()
+                .map(|ast| format!("{:?}", ast))
+        };
+        // the reference is one atom, `{AB}c` is not `a|bc`
+        assert_eq!(
+            parse("{ABC}*"),
+            Ok("Star(And(Or(Char('a'), Char('b')), Char('c')))".to_string())
+        );
+        assert_eq!(parse("{D}"), parse("[0-9]"));
+        assert_eq!(
+            parse("{D}{1,3}").unwrap_err(),
+            ParseError {
+                pos: 3,
+                message: "repetition counts {1,3} are not supported".to_string()
+            }
+        );
+        assert!(parse("x{2}").is_err() && parse("x{2,}").is_err());
+        assert_eq!(parse("{1,2}").unwrap_err().pos, 0);
+        // braces without counts or a name are plain chars
+        assert_eq!(parse("x{,2}"), parse("x\\{,2\\}"));
+        assert_eq!(
+            parse("x{E}").unwrap_err(),
+            ParseError {
+                pos: 1,
+                message: "undefined definition {E}".to_string(),
+            }
+        );
+        let definitions = define(&[("A", "x{B}"), ("B", "{A}y|z"), ("C", "(")]);
+        assert_eq!(
+            definitions.get("A").unwrap_err(),
+            "in {A}: in {B}: {A} refers to itself"
+        );
+        assert_eq!(
+            definitions.get("C").unwrap_err(),
+            "in {C}: empty expression"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse("(ab").unwrap_err().pos, 3);
+        assert_eq!(parse("ab)").unwrap_err().pos, 2);
+        assert_eq!(parse("a|*").unwrap_err().pos, 2);
+        assert!(parse("").is_err());
+        assert!(parse("a||b").is_err());
     }
 }
@@ -0,0 +1,323 @@
+use crate::tables;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+const MAX: u32 = char::MAX as u32;
+const SURROGATE_START: u32 = 0xd800;
+const SURROGATE_END: u32 = 0xdfff;
+
+lazy_static! {
+    // every char that has case variants, paired with all of them, sorted by char
+    static ref CASE_VARIANTS: Vec<(char, Vec<char>)> = {
+        let mut parent: HashMap<char, char> = HashMap::new();
+        fn find(parent: &HashMap<char, char>, mut c: char) -> char {
+            while let Some(&p) = parent.get(&c) {
+                c = p;
+            }
+            c
+        }
+        // only simple mappings count, "ß" does not match "SS"
+        fn single(mut mapping: impl ExactSizeIterator<Item = char>) -> Option<char> {
+            match mapping.len() {
+                1 => mapping.next(),
+                _ => None,
+            }
+        }
+        for c in '\0'..=char::MAX {
+            for other in [single(c.to_lowercase()), single(c.to_uppercase())]
+                .into_iter()
+                .flatten()
+            {
+                let (a, b) = (find(&parent, c), find(&parent, other));
+                if a != b {
+                    parent.insert(a, b);
+                }
+            }
+        }
+        let mut groups: HashMap<char, Vec<char>> = HashMap::new();
+        for &c in parent.keys() {
+            groups.entry(find(&parent, c)).or_default().push(c);
+        }
+        let mut variants = Vec::new();
+        for (root, mut group) in groups {
+            group.push(root);
+            for &c in &group {
+                let others = group.iter().copied().filter(|&o| o != c).collect();
+                variants.push((c, others));
+            }
+        }
+        variants.sort();
+        variants
+    };
+    // the \p{...} classes built so far by name, each one scans every char
+    static ref PROPERTIES: Mutex<HashMap<String, Option<CharClass>>> = Mutex::new(HashMap::new());
+}
+
+// a set of chars stored as sorted, non-overlapping, non-adjacent ranges
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct CharClass {
+    ranges: Vec<(char, char)>,
+}
+
+// the char after `c`, skipping the surrogate gap
+pub fn next_char(c: char) -> Option<char> {
+    match c as u32 {
+        MAX => None,
+        n if n + 1 == SURROGATE_START => Some('\u{e000}'),
+        n => char::from_u32(n + 1),
+    }
+}
+
+fn prev_char(c: char) -> Option<char> {
+    match c as u32 {
+        0 => None,
+        n if n - 1 == SURROGATE_END => Some('\u{d7ff}'),
+        n => char::from_u32(n - 1),
+    }
+}
+
+impl CharClass {
+    pub fn new() -> Self {
+        CharClass { ranges: Vec::new() }
+    }
+    pub fn from_range(lo: char, hi: char) -> Self {
+        let mut class = CharClass::new();
+        class.push(lo, hi);
+        class
+    }
+    pub fn from_ranges(ranges: &[(char, char)]) -> Self {
+        assert!(ranges.iter().all(|(lo, hi)| lo <= hi));
+        let mut class = CharClass {
+            ranges: ranges.to_vec(),
+        };
+        class.normalize();
+        class
+    }
+    pub fn ranges(&self) -> &[(char, char)] {
+        &self.ranges
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+    pub fn contains(&self, c: char) -> bool {
+        self.ranges
+            .binary_search_by(|&(lo, hi)| {
+                if hi < c {
+                    std::cmp::Ordering::Less
+                } else if lo > c {
+                    std::cmp::Ordering::Greater
+                } else {
+                    std::cmp::Ordering::Equal
+                }
+            })
+            .is_ok()
+    }
+    // adds every upper/lower/title case variant of the chars in the class
+    pub fn case_fold(&self) -> CharClass {
+        let mut ranges = self.ranges.clone();
+        for (c, others) in CASE_VARIANTS.iter() {
+            if self.contains(*c) {
+                ranges.extend(others.iter().map(|&o| (o, o)));
+            }
+        }
+        let mut class = CharClass { ranges };
+        class.normalize();
+        class
+    }
+    pub fn push(&mut self, lo: char, hi: char) {
+        assert!(lo <= hi);
+        self.ranges.push((lo, hi));
+        self.normalize();
+    }
+    pub fn union(&mut self, other: &CharClass) {
+        self.ranges.extend_from_slice(&other.ranges);
+        self.normalize();
+    }
+    pub fn negate(&self) -> CharClass {
+        let mut ranges = Vec::new();
+        let mut lo = Some('\0');
+        for &(start, end) in &self.ranges {
+            if let (Some(l), Some(h)) = (lo, prev_char(start)) {
+                if l <= h {
+                    ranges.push((l, h));
+                }
+            }
+            lo = next_char(end);
+        }
+        if let Some(l) = lo {
+            ranges.push((l, char::MAX));
+        }
+        CharClass { ranges }
+    }
+    fn normalize(&mut self) {
+        self.ranges.sort();
+        let mut merged: Vec<(char, char)> = Vec::with_capacity(self.ranges.len());
+        for &(lo, hi) in &self.ranges {
+            if let Some(last) = merged.last_mut() {
+                if next_char(last.1).is_none_or(|n| lo <= n) {
+                    last.1 = last.1.max(hi);
+                    continue;
+                }
+            }
+            merged.push((lo, hi));
+        }
+        self.ranges = merged;
+    }
+
+    pub fn digit() -> Self {
+        CharClass::from_range('0', '9')
+    }
+    pub fn word() -> Self {
+        CharClass::from_ranges(&[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')])
+    }
+    pub fn space() -> Self {
+        CharClass::from_ranges(&[('\t', '\r'), (' ', ' ')])
+    }
+    pub fn any_but_newline() -> Self {
+        CharClass::from_range('\n', '\n').negate()
+    }
+
+    // collects every char satisfying `predicate` into ranges
+    pub fn from_predicate(predicate: impl Fn(char) -> bool) -> Self {
+        let mut ranges: Vec<(char, char)> = Vec::new();
+        for c in ('\0'..=char::MAX).filter(|&c| predicate(c)) {
+            match ranges.last_mut() {
+                Some(last) if next_char(last.1) == Some(c) => last.1 = c,
+                _ => ranges.push((c, c)),
+            }
+        }
+        CharClass { ranges }
+    }
+    // named classes for \p{...}, names are matched ignoring case, '_', '-' and ' '
+    pub fn property(name: &str) -> Option<Self> {
+        let name = name
+            .chars()
+            .filter(|c| !matches!(c, '_' | '-' | ' '))
+            .collect::<String>()
+            .to_lowercase();
+        PROPERTIES
+            .lock()
+            .unwrap()
+            .entry(name)
+            .or_insert_with_key(|name| Self::build_property(name))
+            .clone()
+    }
+    fn build_property(name: &str) -> Option<Self> {
+        let class = match name {
+            "alphabetic" | "alpha" => CharClass::from_predicate(char::is_alphabetic),
+            "numeric" | "n" => CharClass::from_predicate(char::is_numeric),
+            "letter" | "l" => {
+                let non_letters = CharClass::from_ranges(tables::ALPHABETIC_NON_LETTERS);
+                CharClass::from_predicate(|c| c.is_alphabetic() && !non_letters.contains(c))
+            }
+            "decimalnumber" | "nd" => CharClass::from_ranges(tables::DECIMAL_DIGITS),
+            "alphanumeric" | "alnum" => CharClass::from_predicate(char::is_alphanumeric),
+            "whitespace" | "space" => CharClass::from_predicate(char::is_whitespace),
+            "lowercase" | "lower" => CharClass::from_predicate(char::is_lowercase),
+            "uppercase" | "upper" => CharClass::from_predicate(char::is_uppercase),
+            "control" | "cc" => CharClass::from_predicate(char::is_control),
+            "ascii" => CharClass::from_range('\0', '\x7f'),
+            "any" => CharClass::new().negate(),
+            // identifier classes in the spirit of XID_Start/XID_Continue, derived
+            // from the std predicates and with '_' allowed at the start
+            "idstart" | "xidstart" => CharClass::from_predicate(|c| c.is_alphabetic() || c == '_'),
+            "idcontinue" | "xidcontinue" => {
+                CharClass::from_predicate(|c| c.is_alphanumeric() || c == '_')
+            }
+            _ => return None,
+        };
+        Some(class)
+    }
+}
+
+impl fmt::Display for CharClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[")?;
+        for &(lo, hi) in &self.ranges {
+            if lo == hi {
+                write!(f, "{}", lo.escape_debug())?;
+            } else {
+                write!(f, "{}-{}", lo.escape_debug(), hi.escape_debug())?;
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let class = CharClass::from_ranges(&[('d', 'f'), ('a', 'c'), ('x', 'z'), ('e', 'g')]);
+        assert_eq!(class.ranges(), &[('a', 'g'), ('x', 'z')]);
+    }
+
+    #[test]
+    fn test_negate() {
+        let class = CharClass::digit().negate();
+        assert_eq!(class.ranges(), &[('\0', '/'), (':', char::MAX)]);
+        assert_eq!(class.negate(), CharClass::digit());
+        assert_eq!(CharClass::new().negate().ranges(), &[('\0', char::MAX)]);
+        assert!(CharClass::new().negate().negate().is_empty());
+    }
+
+    #[test]
+    fn test_property() {
+        let alpha = CharClass::property("Alphabetic").unwrap();
+        assert_eq!(alpha, CharClass::property("alpha").unwrap());
+        assert!(alpha.ranges().contains(&('a', 'z')));
+        assert!(alpha
+            .ranges()
+            .iter()
+            .any(|&(lo, hi)| lo <= '正' && '正' <= hi));
+        assert!(alpha.ranges().len() < 1000);
+        let space = CharClass::property("White_Space").unwrap();
+        assert_eq!(space.ranges()[..2], [('\t', '\r'), (' ', ' ')]);
+        let start = CharClass::property("XID_Start").unwrap();
+        assert!(start.ranges().contains(&('_', '_')));
+        let letter = CharClass::property("Letter").unwrap();
+        assert_eq!(letter, CharClass::property("L").unwrap());
+        assert!(letter.contains('a') && letter.contains('正') && letter.contains('ª'));
+        // marks and letter numbers are alphabetic but not letters
+        assert!(alpha.contains('\u{903}') && !letter.contains('\u{903}'));
+        assert!(alpha.contains('Ⅻ') && !letter.contains('Ⅻ'));
+        let digits = CharClass::property("Nd").unwrap();
+        assert_eq!(digits, CharClass::property("Decimal_Number").unwrap());
+        assert_eq!(digits.ranges()[0], ('0', '9'));
+        assert!(digits.contains('٣') && digits.contains('\u{1d7ce}'));
+        // numeric but not decimal digits
+        assert!(!digits.contains('½') && !digits.contains('²') && !digits.contains('Ⅻ'));
+        for &(lo, hi) in digits.ranges() {
+            assert_eq!((hi as u32 - lo as u32 + 1) % 10, 0);
+            assert!((lo..=hi).all(char::is_numeric));
+        }
+        assert!(CharClass::property("Digit_Value").is_none());
+    }
+
+    #[test]
+    fn test_case_fold() {
+        let class = CharClass::from_range('a', 'c').case_fold();
+        assert_eq!(class.ranges(), &[('A', 'C'), ('a', 'c')]);
+        let class = CharClass::from_range('k', 'k').case_fold();
+        assert_eq!(
+            class.ranges(),
+            &[('K', 'K'), ('k', 'k'), ('\u{212a}', '\u{212a}')]
+        );
+        let class = CharClass::from_range('σ', 'σ').case_fold();
+        assert_eq!(class.ranges(), &[('Σ', 'Σ'), ('ς', 'σ')]);
+        assert_eq!(CharClass::digit().case_fold(), CharClass::digit());
+    }
+
+    #[test]
+    fn test_surrogate_gap() {
+        let class = CharClass::from_ranges(&[('a', '\u{d7ff}'), ('\u{e000}', '\u{ffff}')]);
+        assert_eq!(class.ranges(), &[('a', '\u{ffff}')]);
+        assert_eq!(
+            class.negate().ranges(),
+            &[('\0', '`'), ('\u{10000}', char::MAX)]
+        );
+    }
+}
@@ -1,36 +1,88 @@
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Tag(pub String);
 
-pub fn input2internal(input_char: char) -> char {
-    match input_char {
-        '.' => '\u{1000}',
-        '*' => '\u{1001}',
-        '+' => '\u{1002}',
-        '?' => '\u{1003}',
-        '(' => '\u{1004}',
-        ')' => '\u{1005}',
-        '[' => '\u{1006}',
-        ']' => '\u{1007}',
-        '|' => '\u{1008}',
-        '-' => '\u{1009}',
-        '\\' => '\u{100a}',
-        _ => input_char,
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub pattern: String,
+    pub tag: Tag,
+    pub case_insensitive: bool,
+    // `<A,B>` in front of the rule, empty means INITIAL and every `%s` mode
+    pub start_conditions: Vec<String>,
+    pub mode_action: Option<ModeAction>,
+    // `%prio(N)`, when several rules match the same longest string the one with
+    // the highest priority wins, rules without one count as 0 and the first
+    // rule wins among them
+    pub priority: Option<i32>,
+    // the flex action, kept for code generators
+    pub action: Option<String>,
+    // where the rule is in the spec file
+    pub line: Option<usize>,
+}
+
+impl Rule {
+    pub fn new(pattern: String, tag: Tag) -> Self {
+        Rule {
+            pattern,
+            tag,
+            case_insensitive: false,
+            start_conditions: Vec::new(),
+            mode_action: None,
+            priority: None,
+            action: None,
+            line: None,
+        }
+    }
+}
+
+impl From<(String, Tag)> for Rule {
+    fn from((pattern, tag): (String, Tag)) -> Self {
+        Rule::new(pattern, tag)
     }
 }
 
-pub fn internal2input(internal_char: char) -> char {
-    match internal_char {
-        '\u{1000}' => '.',
-        '\u{1001}' => '*',
-        '\u{1002}' => '+',
-        '\u{1003}' => '?',
-        '\u{1004}' => '(',
-        '\u{1005}' => ')',
-        '\u{1006}' => '[',
-        '\u{1007}' => ']',
-        '\u{1008}' => '|',
-        '\u{1009}' => '-',
-        '\u{100a}' => '\\',
-        _ => internal_char,
+// what a rule does to the lexer mode after matching, like flex's
+// BEGIN, yy_push_state and yy_pop_state
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ModeAction {
+    Begin(String),
+    Push(String),
+    Pop,
+}
+
+// a `%s` (inclusive) or `%x` (exclusive) start condition, INITIAL is implicit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StartCondition {
+    pub name: String,
+    pub exclusive: bool,
+}
+
+pub const INITIAL: &str = "INITIAL";
+
+// `NAME regex`, used as `{NAME}` in patterns
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definition {
+    pub name: String,
+    pub pattern: String,
+    pub line: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LexSpec {
+    pub start_conditions: Vec<StartCondition>,
+    pub rules: Vec<Rule>,
+    pub definitions: Vec<Definition>,
+    // `%{ %}` blocks and indented lines, in the order they appear
+    pub code: Vec<String>,
+    // everything after the second `%%`
+    pub user_code: String,
+}
+
+impl<R: Into<Rule>> From<Vec<R>> for LexSpec {
+    fn from(rules: Vec<R>) -> Self {
+        LexSpec {
+            start_conditions: Vec::new(),
+            rules: rules.into_iter().map(Into::into).collect(),
+            ..LexSpec::default()
+        }
     }
 }
@@ -0,0 +1,339 @@
+use crate::ast::AstNode;
+use crate::class::CharClass;
+use crate::dfa;
+use crate::nfa::StateSet;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// a regex kept in a normal form by the constructors below, so that the
+// derivatives of a regex are finitely many up to equality
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Re {
+    // matches nothing
+    Empty,
+    Epsilon,
+    Chars(Rc<CharClass>),
+    // the left side is never a `Concat`
+    Concat(Rc<Re>, Rc<Re>),
+    // sorted and without duplicates, nested `Or`s and `Chars` merged
+    Or(Rc<[Re]>),
+    // sorted and without duplicates, nested `And`s merged
+    And(Rc<[Re]>),
+    Star(Rc<Re>),
+    Not(Rc<Re>),
+}
+
+// matches every string
+fn any() -> Re {
+    Re::Not(Rc::new(Re::Empty))
+}
+
+fn chars(class: CharClass) -> Re {
+    match class.is_empty() {
+        true => Re::Empty,
+        false => Re::Chars(Rc::new(class)),
+    }
+}
+
+fn concat(left: Re, right: Re) -> Re {
+    match (left, right) {
+        (Re::Empty, _) | (_, Re::Empty) => Re::Empty,
+        (Re::Epsilon, re) | (re, Re::Epsilon) => re,
+        (Re::Concat(a, b), right) => concat((*a).clone(), concat((*b).clone(), right)),
+        (left, right) => Re::Concat(Rc::new(left), Rc::new(right)),
+    }
+}
+
+fn or(items: impl IntoIterator<Item = Re>) -> Re {
+    let mut flat = Vec::new();
+    let mut class = CharClass::new();
+    for item in items {
+        match item {
+            Re::Empty => {}
+            Re::Or(items) => {
+                for item in items.iter() {
+                    match item {
+                        Re::Chars(c) => class.union(c),
+                        item => flat.push(item.clone()),
+                    }
+                }
+            }
+            Re::Chars(c) => class.union(&c),
+            item => flat.push(item),
+        }
+    }
+    if flat.contains(&any()) {
+        return any();
+    }
+    if !class.is_empty() {
+        flat.push(chars(class));
+    }
+    flat.sort();
+    flat.dedup();
+    match flat.len() {
+        0 => Re::Empty,
+        1 => flat.pop().unwrap(),
+        _ => Re::Or(flat.into()),
+    }
+}
+
+fn and(items: impl IntoIterator<Item = Re>) -> Re {
+    let mut flat = Vec::new();
+    for item in items {
+        match item {
+            Re::And(items) => flat.extend(items.iter().cloned()),
+            item => flat.push(item),
+        }
+    }
+    if flat.contains(&Re::Empty) {
+        return Re::Empty;
+    }
+    flat.retain(|item| *item != any());
+    flat.sort();
+    flat.dedup();
+    match flat.len() {
+        0 => any(),
+        1 => flat.pop().unwrap(),
+        _ => Re::And(flat.into()),
+    }
+}
+
+fn star(re: Re) -> Re {
+    match re {
+        Re::Empty | Re::Epsilon => Re::Epsilon,
+        Re::Star(_) => re,
+        Re::Chars(class) if class.negate().is_empty() => any(),
+        re => Re::Star(Rc::new(re)),
+    }
+}
+
+fn not(re: Re) -> Re {
+    match re {
+        Re::Not(re) => (*re).clone(),
+        re => Re::Not(Rc::new(re)),
+    }
+}
+
+impl Re {
+    pub fn new(node: &AstNode) -> Re {
+        Re::with_case(node, false)
+    }
+    fn with_case(node: &AstNode, ignore_case: bool) -> Re {
+        let class = |class: CharClass| match ignore_case {
+            true => chars(class.case_fold()),
+            false => chars(class),
+        };
+        let re = |node: &AstNode| Re::with_case(node, ignore_case);
+        match node {
+            AstNode::Char(c) => class(CharClass::from_range(*c, *c)),
+            AstNode::Class(c) => class(c.clone()),
+            AstNode::IgnoreCase(node) => Re::with_case(node, true),
+            AstNode::And(left, right) => concat(re(left), re(right)),
+            AstNode::Or(left, right) => or([re(left), re(right)]),
+            AstNode::Intersect(left, right) => and([re(left), re(right)]),
+            AstNode::Complement(node) => not(re(node)),
+            AstNode::Star(node) => star(re(node)),
+            AstNode::Plus(node) => {
+                let node = re(node);
+                concat(node.clone(), star(node))
+            }
+            AstNode::Question(node) => or([re(node), Re::Epsilon]),
+        }
+    }
+    pub fn nullable(&self) -> bool {
+        match self {
+            Re::Empty | Re::Chars(_) => false,
+            Re::Epsilon | Re::Star(_) => true,
+            Re::Concat(left, right) => left.nullable() && right.nullable(),
+            Re::Or(items) => items.iter().any(Re::nullable),
+            Re::And(items) => items.iter().all(Re::nullable),
+            Re::Not(re) => !re.nullable(),
+        }
+    }
+    // the strings `s` for which `c` followed by `s` matches
+    pub fn derivative(&self, c: char) -> Re {
+        match self {
+            Re::Empty | Re::Epsilon => Re::Empty,
+            Re::Chars(class) => match class.contains(c) {
+                true => Re::Epsilon,
+                false => Re::Empty,
+            },
+            Re::Concat(left, right) => {
+                let first = concat(left.derivative(c), (**right).clone());
+                match left.nullable() {
+                    true => or([first, right.derivative(c)]),
+                    false => first,
+                }
+            }
+            Re::Or(items) => or(items.iter().map(|re| re.derivative(c))),
+            Re::And(items) => and(items.iter().map(|re| re.derivative(c))),
+            Re::Star(re) => concat(re.derivative(c), self.clone()),
+            Re::Not(re) => not(re.derivative(c)),
+        }
+    }
+    // the ends of the char sets whose chars all have the same derivative
+    fn boundaries(&self, points: &mut Vec<u32>) {
+        match self {
+            Re::Empty | Re::Epsilon => {}
+            Re::Chars(class) => {
+                for &(lo, hi) in class.ranges() {
+                    points.push(lo as u32);
+                    points.push(hi as u32 + 1);
+                }
+            }
+            Re::Concat(left, right) => {
+                left.boundaries(points);
+                if left.nullable() {
+                    right.boundaries(points);
+                }
+            }
+            Re::Or(items) | Re::And(items) => {
+                for re in items.iter() {
+                    re.boundaries(points);
+                }
+            }
+            Re::Star(re) | Re::Not(re) => re.boundaries(points),
+        }
+    }
+    // the derivatives that aren't empty, for the ranges of chars leading to them
+    pub fn moves(&self) -> Vec<(char, char, Re)> {
+        let mut points = vec![0, char::MAX as u32 + 1];
+        self.boundaries(&mut points);
+        points.sort_unstable();
+        points.dedup();
+        let mut moves: Vec<(char, char, Re)> = Vec::new();
+        for window in points.windows(2) {
+            // the interval may start or end inside the surrogate gap
+            let lo = char::from_u32(window[0]).unwrap_or('\u{e000}');
+            let hi = char::from_u32(window[1] - 1).unwrap_or('\u{d7ff}');
+            if lo > hi {
+                continue;
+            }
+            let next = self.derivative(lo);
+            if next == Re::Empty {
+                continue;
+            }
+            match moves.last_mut() {
+                Some(last) if last.2 == next && crate::class::next_char(last.1) == Some(lo) => {
+                    last.1 = hi
+                }
+                _ => moves.push((lo, hi, next)),
+            }
+        }
+        moves
+    }
+}
+
+// the derivatives of every rule reached so far, a dfa state is the set of
+// the derivatives its input leads every rule to
+#[derive(Default)]
+pub struct Derivatives {
+    index: HashMap<(usize, Re), usize>,
+    // the rule and the regex of every member
+    members: Vec<(usize, Re)>,
+    nullable: Vec<bool>,
+    // the moves of every member, with the members they lead to
+    moves: Vec<Option<Vec<(char, char, usize)>>>,
+}
+
+impl Derivatives {
+    pub fn add(&mut self, rule: usize, re: Re) -> usize {
+        if let Some(&member) = self.index.get(&(rule, re.clone())) {
+            return member;
+        }
+        self.nullable.push(re.nullable());
+        self.members.push((rule, re.clone()));
+        self.moves.push(None);
+        self.index.insert((rule, re), self.members.len() - 1);
+        self.members.len() - 1
+    }
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+    pub fn rule(&self, member: usize) -> usize {
+        self.members[member].0
+    }
+    pub fn accepts(&self, member: usize) -> Option<usize> {
+        self.nullable[member].then_some(self.members[member].0)
+    }
+    // splits the chars leaving a dfa state by the state they lead to
+    pub fn state_moves(
+        &mut self,
+        members: &[usize],
+        set: &mut StateSet,
+    ) -> Vec<(char, char, Vec<usize>)> {
+        let mut events = Vec::new();
+        for &member in members {
+            if self.moves[member].is_none() {
+                let (rule, re) = self.members[member].clone();
+                let moves = re
+                    .moves()
+                    .into_iter()
+                    .map(|(lo, hi, next)| (lo, hi, self.add(rule, next)))
+                    .collect();
+                self.moves[member] = Some(moves);
+            }
+            for &(lo, hi, next) in self.moves[member].as_ref().unwrap() {
+                events.push((lo as u32, true, next));
+                events.push((hi as u32 + 1, false, next));
+            }
+        }
+        set.reserve(self.len());
+        dfa::split_ranges(events, std::iter::once, set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{LexSpec, Tag};
+    use crate::dfa::tests::assert_same_as_thompson;
+    use crate::dfa::{Dfa, Limits};
+
+    fn re(pattern: &str) -> Re {
+        let ast = crate::ast::Parser::new(pattern.to_string())
+            .parse_pattern()
+            .unwrap()
+            .ast;
+        Re::new(&ast)
+    }
+
+    #[test]
+    fn test_normal_form() {
+        // or is associative, commutative and idempotent
+        assert_eq!(re("ab|cd|ab"), re("cd|(ab|cd)"));
+        assert_eq!(re("(a|b)|c"), re("[a-c]"));
+        assert_eq!(re("ab&cd"), re("cd&ab&cd"));
+        assert_eq!(re("(a*)*"), re("a*"));
+        assert_eq!(re("~~(ab)"), re("ab"));
+        assert_eq!(re("(ab)?"), or([Re::Epsilon, re("ab")]));
+        assert_eq!(re("\\p{Any}*"), any());
+        assert_eq!(re("a|\\p{Any}*"), any());
+        assert!(re("a*").nullable());
+        assert!(!re("a+").nullable());
+        assert!(re("~a").nullable());
+    }
+
+    #[test]
+    fn test_derivative() {
+        let r = re("(a|b)*abb");
+        assert_eq!(r.derivative('c'), Re::Empty);
+        assert_eq!(r.derivative('b'), r);
+        assert_eq!(r.derivative('a'), or([r.clone(), re("bb")]));
+        // the derivatives repeat, so the dfa of the dragon book comes out
+        let spec = LexSpec::from(vec![("(a|b)*abb".to_string(), Tag("ABB".to_string()))]);
+        let dfa = Dfa::from_derivatives(spec, &Limits::default()).unwrap();
+        assert_eq!(dfa.states.len(), 4);
+        let moves = re("[a-z]x|[m-p]y").moves();
+        let ranges = moves
+            .iter()
+            .map(|&(lo, hi, _)| (lo, hi))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, [('a', 'l'), ('m', 'p'), ('q', 'z')]);
+    }
+
+    #[test]
+    fn test_against_thompson() {
+        assert_same_as_thompson(|spec| Dfa::from_derivatives(spec, &Limits::default()).unwrap());
+    }
+}
@@ -1,279 +1,989 @@
-use crate::common::Tag;
-use crate::nfa::{self, Nfa};
-use petgraph::dot::{Config, Dot};
+use crate::alphabet::Alphabet;
+use crate::ast::{AstNode, ParseError, TrailingContext};
+use crate::class::{next_char, CharClass};
+use crate::common::{LexSpec, ModeAction, Tag};
+use crate::derivative::{Derivatives, Re};
+use crate::followpos::Positions;
+use crate::nfa::{self, Nfa, StateSet};
+use petgraph::dot::Dot;
 use petgraph::graph::DiGraph;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Hash, Eq, PartialEq, Clone)]
-enum Transition {
-    Char(char),
+// outgoing edges and whether the state accepts, for automata built without tags
+type EdgeList = (Vec<(char, char, usize)>, bool);
+// the parts of an accepted rule the lexer acts on
+type Observable<'a> = (
+    &'a Tag,
+    Option<i32>,
+    Option<TrailingContext>,
+    &'a Option<ModeAction>,
+);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    // two rules with the same `%prio` both match `example`, with their lines
+    Ambiguous {
+        rules: [(Tag, Option<usize>); 2],
+        example: String,
+    },
+    // subset construction passed `limit`, `rules` are the ones making the dfa
+    // grow, most first
+    TooLarge {
+        limit: Limit,
+        rules: Vec<(Tag, Option<usize>)>,
+    },
+    // the pattern of a rule doesn't parse
+    Pattern {
+        rule: (Tag, Option<usize>),
+        pattern: String,
+        error: ParseError,
+    },
+    // a rule is active in or switches to a start condition that isn't declared
+    UndeclaredMode {
+        rule: (Tag, Option<usize>),
+        mode: String,
+    },
+}
+
+fn describe_rule((tag, line): &(Tag, Option<usize>)) -> String {
+    match line {
+        Some(line) => format!("{} (line {})", tag.0, line),
+        None => tag.0.clone(),
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Ambiguous { rules, example } => {
+                let [a, b] = rules.each_ref().map(describe_rule);
+                write!(
+                    f,
+                    "rules {} and {} have the same priority and both match {:?}",
+                    a, b, example
+                )
+            }
+            BuildError::TooLarge { limit, rules } => {
+                match limit {
+                    Limit::States(n) => write!(f, "the dfa has more than {} states", n)?,
+                    Limit::Transitions(n) => write!(f, "the dfa has more than {} transitions", n)?,
+                    Limit::Time(time) => {
+                        write!(f, "building the dfa takes longer than {:?}", time)?
+                    }
+                }
+                if !rules.is_empty() {
+                    let rules = rules.iter().map(describe_rule).collect::<Vec<_>>();
+                    let s = if rules.len() > 1 { "s" } else { "" };
+                    write!(f, ", mostly because of rule{} {}", s, rules.join(", "))?;
+                }
+                Ok(())
+            }
+            BuildError::Pattern {
+                rule,
+                pattern,
+                error,
+            } => write!(
+                f,
+                "invalid pattern {:?} for rule {}: {}",
+                pattern,
+                describe_rule(rule),
+                error
+            ),
+            BuildError::UndeclaredMode { rule, mode } => write!(
+                f,
+                "rule {} uses the undeclared start condition {}",
+                describe_rule(rule),
+                mode
+            ),
+        }
+    }
 }
+
+impl std::error::Error for BuildError {}
+
+impl BuildError {
+    // a dfa built for `~r` or `r & s` only knows its own pattern, the limit
+    // is put on the rule the pattern is part of
+    pub fn blame(self, rule: &nfa::Accept) -> BuildError {
+        match self {
+            BuildError::TooLarge { limit, .. } => BuildError::TooLarge {
+                limit,
+                rules: vec![(rule.tag.clone(), rule.line)],
+            },
+            e => e,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct DfaState {
-    transitions: Vec<(Transition, usize)>,
+    // disjoint `lo..=hi` ranges sorted by `lo`, adjacent ranges go to different states
+    transitions: Vec<(char, char, usize)>,
+    // the nfa states the state is made of, or the members of the other constructions
     nfa_states: Vec<usize>,
-    pub accept: Option<Tag>,
+    // index of the accepted rule
+    pub accept: Option<usize>,
+    // the rule to use instead of `accept` when a newline or the end of input follows
+    pub accept_eol: Option<usize>,
 }
-#[derive(Debug)]
+// the blocks of states refined by `Dfa::minimize`, the states of a block are
+// next to each other in `elements` with the marked ones first
+struct Partition {
+    elements: Vec<usize>,
+    // where every state is in `elements`
+    position: Vec<usize>,
+    block: Vec<usize>,
+    // the range of every block in `elements` and how many of its states are marked
+    bounds: Vec<(usize, usize)>,
+    marked: Vec<usize>,
+}
+
+impl Partition {
+    // a block for every distinct key, states with equal keys share one
+    fn new<K: Hash + Eq>(keys: impl Iterator<Item = K>) -> Partition {
+        let mut ids = HashMap::new();
+        let block = keys
+            .map(|key| {
+                let id = ids.len();
+                *ids.entry(key).or_insert(id)
+            })
+            .collect::<Vec<_>>();
+        let mut elements = (0..block.len()).collect::<Vec<_>>();
+        elements.sort_by_key(|&state| block[state]);
+        let mut position = vec![0; block.len()];
+        let mut bounds = vec![(0, 0); ids.len()];
+        for (i, &state) in elements.iter().enumerate() {
+            position[state] = i;
+            let b = block[state];
+            if i == 0 || block[elements[i - 1]] != b {
+                bounds[b].0 = i;
+            }
+            bounds[b].1 = i + 1;
+        }
+        Partition {
+            elements,
+            position,
+            block,
+            marked: vec![0; bounds.len()],
+            bounds,
+        }
+    }
+    fn len(&self) -> usize {
+        self.bounds.len()
+    }
+    fn size(&self, block: usize) -> usize {
+        self.bounds[block].1 - self.bounds[block].0
+    }
+    fn members(&self, block: usize) -> &[usize] {
+        &self.elements[self.bounds[block].0..self.bounds[block].1]
+    }
+    // returns the block of `state` when it is the first state marked there
+    fn mark(&mut self, state: usize) -> Option<usize> {
+        let block = self.block[state];
+        let first_unmarked = self.bounds[block].0 + self.marked[block];
+        let i = self.position[state];
+        if i < first_unmarked {
+            return None;
+        }
+        let other = self.elements[first_unmarked];
+        self.elements.swap(i, first_unmarked);
+        self.position[other] = i;
+        self.position[state] = first_unmarked;
+        self.marked[block] += 1;
+        (self.marked[block] == 1).then_some(block)
+    }
+    // moves the marked states of the blocks into blocks of their own, unless
+    // the whole block is marked, and returns the pairs of old and new blocks
+    fn split(&mut self, blocks: &[usize]) -> Vec<(usize, usize)> {
+        let mut splits = Vec::new();
+        for &block in blocks {
+            let (start, end) = self.bounds[block];
+            let marked = std::mem::take(&mut self.marked[block]);
+            if start + marked == end {
+                continue;
+            }
+            let new = self.bounds.len();
+            self.bounds.push((start, start + marked));
+            self.marked.push(0);
+            self.bounds[block].0 = start + marked;
+            for &state in &self.elements[start..start + marked] {
+                self.block[state] = new;
+            }
+            splits.push((block, new));
+        }
+        splits
+    }
+}
+
+// the budget of subset construction, which can take exponential time and
+// memory, none means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub states: Option<usize>,
+    pub transitions: Option<usize>,
+    pub time: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    States(usize),
+    Transitions(usize),
+    Time(Duration),
+}
+
+// splits the chars into the fewest ranges whose chars all lead to the same
+// set, `events` are where each move `lo..=hi` starts and where it ends,
+// exclusive, with the key of the set `targets` gives for it
+pub fn split_ranges<T: IntoIterator<Item = usize>>(
+    mut events: Vec<(u32, bool, usize)>,
+    targets: impl Fn(usize) -> T,
+    set: &mut StateSet,
+) -> Vec<(char, char, Vec<usize>)> {
+    events.sort();
+    let mut points = events.iter().map(|e| e.0).collect::<Vec<_>>();
+    points.dedup();
+    // how many moves with each key cover the current interval
+    let mut active = BTreeMap::new();
+    let mut events = events.into_iter().peekable();
+    let mut ranges: Vec<(char, char, Vec<usize>)> = Vec::new();
+    for window in points.windows(2) {
+        while let Some((_, starts, next)) = events.next_if(|e| e.0 == window[0]) {
+            let count = active.entry(next).or_insert(0);
+            if starts {
+                *count += 1;
+            } else {
+                *count -= 1;
+                if *count == 0 {
+                    active.remove(&next);
+                }
+            }
+        }
+        // the interval may start or end inside the surrogate gap
+        let lo = char::from_u32(window[0]).unwrap_or('\u{e000}');
+        let hi = char::from_u32(window[1] - 1).unwrap_or('\u{d7ff}');
+        if active.is_empty() || lo > hi {
+            continue;
+        }
+        for &next in active.keys() {
+            for s in targets(next) {
+                set.insert(s);
+            }
+        }
+        let target = set.take();
+        if target.is_empty() {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.2 == target && next_char(last.1) == Some(lo) => last.1 = hi,
+            _ => ranges.push((lo, hi, target)),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+pub type Shape = (
+    Vec<usize>,
+    Vec<(Vec<(char, char, usize)>, Option<usize>, Option<usize>)>,
+);
+
+// a missing transition in `Dfa::table`
+const NO_STATE: u32 = u32::MAX;
+
+#[derive(Debug, Default)]
 pub struct Dfa {
     pub states: Vec<DfaState>,
+    // two start states per mode, see `start`
+    starts: Vec<usize>,
     nfa: Nfa,
+    alphabet: Alphabet,
+    // the next state for every state and char class, row by row
+    table: Vec<u32>,
 }
 
 impl Dfa {
-    pub fn new(pattern: Vec<(String, Tag)>) -> Dfa {
-        let mut nfa = Nfa::new(pattern);
-        nfa.construct();
-        Dfa {
-            states: Vec::new(),
-            nfa,
+    pub fn new<S: Into<LexSpec>>(spec: S) -> Result<Dfa, BuildError> {
+        Ok(Dfa {
+            nfa: Nfa::new(spec)?,
+            ..Dfa::default()
+        })
+    }
+    // determinizes a single pattern, used for the operands of `~r` and `r & s`,
+    // within the limits of the dfa they are part of
+    pub fn from_ast(ast: AstNode, limits: &Limits, started: Instant) -> Result<Dfa, BuildError> {
+        let mut dfa = Dfa {
+            nfa: Nfa::from_asts(vec![(ast.into(), Tag(String::new()))]),
+            ..Dfa::default()
+        };
+        dfa.construct_since(limits, started)?;
+        Ok(dfa)
+    }
+    // the ties of equal `%prio` that building the dfa reports, for the backends
+    // that don't build it, only the rules that can tie or beat a tie take part,
+    // and only the strings on which two rules of the same `%prio` can both
+    // still match are followed, so the rest of the dfa is never built
+    pub fn check_priorities(spec: &LexSpec) -> Result<(), BuildError> {
+        let mut priorities = spec
+            .rules
+            .iter()
+            .filter_map(|rule| rule.priority)
+            .collect::<Vec<_>>();
+        priorities.sort_unstable();
+        let Some(lowest) = priorities.windows(2).find(|w| w[0] == w[1]).map(|w| w[0]) else {
+            return Ok(());
+        };
+        let rules = spec
+            .rules
+            .iter()
+            .filter(|rule| rule.priority.unwrap_or(0) >= lowest)
+            .cloned()
+            .collect();
+        let mut nfa = Nfa::new(LexSpec {
+            rules,
+            ..spec.clone()
+        })?;
+        nfa.construct()?;
+        // the rule and `%prio` of every nfa state of a rule with one
+        let owners = (0..nfa.states.len())
+            .map(|state| {
+                let rule = nfa.rule_owning(state)?;
+                Some((rule, nfa.accept[rule].priority?))
+            })
+            .collect::<Vec<_>>();
+        let tied = |states: &[usize]| {
+            let mut first = HashMap::new();
+            states
+                .iter()
+                .filter_map(|&state| owners[state])
+                .any(|(rule, priority)| *first.entry(priority).or_insert(rule) != rule)
+        };
+        Dfa::default().subset_construction(&nfa, (&Limits::default(), Instant::now()), tied)
+    }
+    // the states that can still reach an accepting state
+    pub fn live_states(&self) -> Vec<bool> {
+        let mut live = self
+            .states
+            .iter()
+            .map(|state| state.accept.is_some())
+            .collect::<Vec<_>>();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in 0..self.states.len() {
+                if !live[i] && self.edges(i).iter().any(|&(_, _, next)| live[next]) {
+                    live[i] = true;
+                    changed = true;
+                }
+            }
         }
+        live
     }
-    pub fn get_next_state(&self, state: usize, c: char) -> Option<usize> {
-        for (transition, next) in &self.states[state].transitions {
-            let Transition::Char(ch) = transition;
-            if *ch == c {
-                return Some(*next);
+    // the start states and every state, equal for equivalent minimized dfas
+    // since minimizing numbers the states canonically
+    #[cfg(test)]
+    pub fn shape(&self) -> Shape {
+        let starts = (0..self.modes().len())
+            .flat_map(|mode| [self.start(mode, false), self.start(mode, true)])
+            .collect();
+        let states = (0..self.states.len())
+            .map(|s| {
+                (
+                    self.edges(s).to_vec(),
+                    self.states[s].accept,
+                    self.states[s].accept_eol,
+                )
+            })
+            .collect();
+        (starts, states)
+    }
+    pub fn edges(&self, state: usize) -> &[(char, char, usize)] {
+        &self.states[state].transitions
+    }
+    fn from_edges(states: Vec<EdgeList>) -> Dfa {
+        let states = states
+            .into_iter()
+            .map(|(mut edges, accept)| DfaState {
+                transitions: {
+                    edges.sort();
+                    edges
+                },
+                nfa_states: Vec::new(),
+                accept: accept.then_some(0),
+                accept_eol: accept.then_some(0),
+            })
+            .collect();
+        let mut dfa = Dfa {
+            states,
+            starts: vec![0, 0],
+            ..Dfa::default()
+        };
+        dfa.build_table();
+        dfa
+    }
+    // completes the automaton with a dead state and flips every accept
+    pub fn complement(&self) -> Dfa {
+        let dead = self.states.len();
+        let mut states = Vec::new();
+        for (i, state) in self.states.iter().enumerate() {
+            let mut edges = self.edges(i).to_vec();
+            let covered = CharClass::from_ranges(
+                &edges
+                    .iter()
+                    .map(|&(lo, hi, _)| (lo, hi))
+                    .collect::<Vec<_>>(),
+            );
+            for &(lo, hi) in covered.negate().ranges() {
+                edges.push((lo, hi, dead));
             }
+            states.push((edges, state.accept.is_none()));
         }
-        None
+        states.push((vec![('\0', char::MAX, dead)], true));
+        Dfa::from_edges(states)
     }
-
-    fn get_epsilon_closure(&self, state: usize) -> Vec<usize> {
-        let mut closure = Vec::new();
-        let mut stack = Vec::new();
-        closure.push(state);
-        stack.push(state);
-        while let Some(s) = stack.pop() {
-            for (t, next) in self.nfa.states[s].transitions.iter() {
-                if let nfa::Transition::Epsilon = t {
-                    if !closure.contains(next) {
-                        closure.push(*next);
-                        stack.push(*next);
+    // product construction, only pairs reachable from the two start states are built
+    pub fn intersect(&self, other: &Dfa) -> Dfa {
+        let mut index = HashMap::new();
+        let mut pairs = vec![(0, 0)];
+        let mut states = Vec::new();
+        index.insert((0, 0), 0);
+        while states.len() < pairs.len() {
+            let (a, b) = pairs[states.len()];
+            let mut edges = Vec::new();
+            for &(lo1, hi1, next1) in self.edges(a) {
+                for &(lo2, hi2, next2) in other.edges(b) {
+                    let (lo, hi) = (lo1.max(lo2), hi1.min(hi2));
+                    if lo > hi {
+                        continue;
                     }
+                    let next = *index.entry((next1, next2)).or_insert_with(|| {
+                        pairs.push((next1, next2));
+                        pairs.len() - 1
+                    });
+                    edges.push((lo, hi, next));
                 }
             }
+            let accept = self.states[a].accept.is_some() && other.states[b].accept.is_some();
+            states.push((edges, accept));
         }
-        closure
+        Dfa::from_edges(states)
     }
-    fn compute_nfa_transition_range(&self, states: Vec<usize>) -> Vec<char> {
-        let mut range = Vec::new();
-        for s in states {
-            for (t, next) in self.nfa.states[s].transitions.iter() {
-                if let nfa::Transition::Symbol(c) = t {
-                    if !range.contains(c) {
-                        range.push(*c);
-                    }
+    pub fn get_next_state(&self, state: usize, c: char) -> Option<usize> {
+        let next = self.table[state * self.alphabet.len() + self.alphabet.class(c)];
+        (next != NO_STATE).then_some(next as usize)
+    }
+    // looks the char up in the ranges instead of the table
+    fn next_state_by_ranges(&self, state: usize, c: char) -> Option<usize> {
+        let transitions = &self.states[state].transitions;
+        let i = transitions.partition_point(|&(_, hi, _)| hi < c);
+        transitions
+            .get(i)
+            .filter(|&&(lo, _, _)| lo <= c)
+            .map(|&(_, _, next)| next)
+    }
+
+    // splits the chars leaving `states` into the fewest ranges whose chars all
+    // lead to the same states, returned with the epsilon closure of those states
+    fn partition(
+        nfa: &Nfa,
+        states: &[usize],
+        closures: &HashMap<usize, Vec<usize>>,
+        set: &mut StateSet,
+    ) -> Vec<(char, char, Vec<usize>)> {
+        let mut events = Vec::new();
+        for &s in states {
+            for (t, next) in &nfa.states[s].transitions {
+                if let nfa::Transition::Range(lo, hi) = t {
+                    events.push((*lo as u32, true, *next));
+                    events.push((*hi as u32 + 1, false, *next));
                 }
             }
         }
-        range
+        split_ranges(events, |next| closures[&next].iter().copied(), set)
+    }
+    // the start state of `mode`, `bol` selects the one used at the beginning of a line
+    pub fn start(&self, mode: usize, bol: bool) -> usize {
+        self.starts[2 * mode + bol as usize]
+    }
+    pub fn modes(&self) -> &[String] {
+        &self.nfa.modes
     }
-    fn calculate_accept(nfa_accept: Vec<(usize, Tag)>, states: Vec<usize>) -> Option<Tag> {
-        let mut accepts = Vec::new();
-        for state in states {
-            for (accept, tag) in nfa_accept.iter() {
-                if state == *accept {
-                    accepts.push(tag.clone());
+    pub fn rule(&self, rule: usize) -> &nfa::Accept {
+        &self.nfa.accept[rule]
+    }
+    // what a state accepts as far as the lexer can tell, rules that differ only
+    // in their index or line accept the same, the dfas of `~r` and `r & s`
+    // have no rules
+    fn observable(&self, state: usize) -> [Option<Option<Observable<'_>>>; 2] {
+        let state = &self.states[state];
+        [state.accept, state.accept_eol].map(|rule| {
+            rule.map(|rule| {
+                self.nfa
+                    .accept
+                    .get(rule)
+                    .map(|r| (&r.tag, r.priority, r.trailing, &r.mode_action))
+            })
+        })
+    }
+    // the rule with the highest priority among the sorted `rules`, then the
+    // first one, `eol` also counts the rules that only match at the end of a line
+    pub fn winning_rule(nfa_accept: &[nfa::Accept], rules: &[usize], eol: bool) -> Option<usize> {
+        rules
+            .iter()
+            .copied()
+            .filter(|&rule| eol || !nfa_accept[rule].eol)
+            .rev()
+            .max_by_key(|&rule| nfa_accept[rule].priority.unwrap_or(0))
+    }
+    // returns the accepted rule and the one accepted at the end of a line, or
+    // the two rules with the same `%prio` that tie
+    fn calculate_accept(
+        nfa_accept: &[nfa::Accept],
+        rules: &[usize],
+    ) -> Result<(Option<usize>, Option<usize>), (usize, usize)> {
+        let best = |eol: bool| {
+            let Some(rule) = Self::winning_rule(nfa_accept, rules, eol) else {
+                return Ok(None);
+            };
+            let priority = nfa_accept[rule].priority;
+            let tie = rules.iter().copied().find(|&other| {
+                other != rule
+                    && (eol || !nfa_accept[other].eol)
+                    && priority.is_some()
+                    && nfa_accept[other].priority == priority
+            });
+            match tie {
+                Some(other) => Err((rule, other)),
+                None => Ok(Some(rule)),
+            }
+        };
+        Ok((best(false)?, best(true)?))
+    }
+    // splits the chars into classes and fills the table, has to run again
+    // whenever the transitions change
+    fn build_table(&mut self) {
+        let ranges = self
+            .states
+            .iter()
+            .flat_map(|state| state.transitions.iter().map(|&(lo, hi, _)| (lo, hi)));
+        self.alphabet = Alphabet::new(self.states.len(), ranges, |state, c| {
+            self.next_state_by_ranges(state, c)
+        });
+        self.table = Vec::with_capacity(self.states.len() * self.alphabet.len());
+        for state in 0..self.states.len() {
+            for &c in self.alphabet.representatives() {
+                let next = self.next_state_by_ranges(state, c);
+                self.table.push(next.map_or(NO_STATE, |next| next as u32));
+            }
+        }
+    }
+    // a shortest input that leads from a start state to `state`
+    fn example(&self, state: usize) -> String {
+        let mut parents = vec![None; self.states.len()];
+        let mut queue = self.starts.iter().copied().collect::<VecDeque<_>>();
+        for &start in &self.starts {
+            parents[start] = Some((start, None));
+        }
+        while let Some(s) = queue.pop_front() {
+            for &(lo, _, next) in self.edges(s) {
+                if parents[next].is_none() {
+                    parents[next] = Some((s, Some(lo)));
+                    queue.push_back(next);
                 }
             }
         }
-        if accepts.is_empty() {
-            None
-        // } else if accepts.len() != 1 {
-        //     panic!("Ambiguous accept state")
-        } else {
-            Some(accepts[0].clone())
+        let mut example = Vec::new();
+        let mut s = state;
+        while let Some((parent, Some(c))) = parents[s] {
+            example.push(c);
+            s = parent;
         }
+        example.iter().rev().collect()
     }
-    pub fn construct(&mut self) {
-        let mut stack = Vec::new();
-        let mut start = self.get_epsilon_closure(self.nfa.start);
-        self.states.push(DfaState {
-            transitions: Vec::new(),
-            nfa_states: start.clone(),
-            accept: None,
-        });
-
-        stack.push(self.states.len() - 1);
-
-        while let Some(s) = stack.pop() {
-            let range = self.compute_nfa_transition_range(self.states[s].nfa_states.clone());
-            for c in range {
-                let mut next = Vec::new();
-                for nfa_state in self.states[s].nfa_states.clone() {
-                    for (t, next_state) in self.nfa.states[nfa_state].transitions.iter() {
-                        if let nfa::Transition::Symbol(ch) = t {
-                            if *ch == c {
-                                next.append(&mut self.get_epsilon_closure(*next_state));
-                            }
-                        }
-                    }
+    // without limits, the lexer always passes the ones of its builder
+    #[cfg(test)]
+    pub fn construct(&mut self) -> Result<(), BuildError> {
+        self.construct_within(&Limits::default())
+    }
+    // the direct construction of the dragon book, from the followpos of the
+    // positions of the rules instead of an nfa
+    pub fn from_followpos<S: Into<LexSpec>>(spec: S, limits: &Limits) -> Result<Dfa, BuildError> {
+        let started = Instant::now();
+        let mut nfa = Nfa::new(spec)?;
+        let mut positions = Positions::default();
+        let mut starts = vec![Vec::new(); 2 * nfa.modes.len()];
+        for (rule, (ast, bol, modes)) in nfa.patterns().iter().enumerate() {
+            let first = positions
+                .add_rule(rule, ast, limits, started)
+                .map_err(|e| e.blame(&nfa.accept[rule]))?;
+            for &mode in modes {
+                if !bol {
+                    starts[2 * mode].extend_from_slice(&first);
                 }
-                if !next.is_empty() {
-                    next.sort();
-                    next.dedup();
-                    if let Some(pos) = self
-                        .states
-                        .iter()
-                        .position(|state| state.nfa_states == next)
-                    {
-                        self.states[s].transitions.push((Transition::Char(c), pos));
-                    } else {
-                        self.states.push(DfaState {
-                            transitions: Vec::new(),
-                            nfa_states: next.clone(),
-                            accept: None,
-                        });
-                        stack.push(self.states.len() - 1);
-                        let pos = self.states.len() - 1;
-                        self.states[s].transitions.push((Transition::Char(c), pos));
-                    }
+                starts[2 * mode + 1].extend_from_slice(&first);
+            }
+        }
+        positions.finish();
+        for start in &mut starts {
+            start.sort_unstable();
+        }
+        let mut set = StateSet::new(positions.symbols.len());
+        let mut dfa = Dfa::default();
+        dfa.build_states(
+            starts,
+            |members| positions.moves(members, &mut set),
+            |p| positions.accepts(p),
+            |p| Some(positions.rules[p]),
+            &nfa.accept,
+            (limits, started),
+        )?;
+        dfa.nfa = nfa;
+        Ok(dfa)
+    }
+    // Brzozowski's construction, a dfa state is made of the derivatives of the
+    // rules by its input, which are normalized so that there are finitely many
+    pub fn from_derivatives<S: Into<LexSpec>>(spec: S, limits: &Limits) -> Result<Dfa, BuildError> {
+        let started = Instant::now();
+        let mut nfa = Nfa::new(spec)?;
+        let derivatives = RefCell::new(Derivatives::default());
+        let mut starts = vec![Vec::new(); 2 * nfa.modes.len()];
+        for (rule, (ast, bol, modes)) in nfa.patterns().iter().enumerate() {
+            let member = derivatives.borrow_mut().add(rule, Re::new(ast));
+            for &mode in modes {
+                if !bol {
+                    starts[2 * mode].push(member);
                 }
+                starts[2 * mode + 1].push(member);
             }
         }
-        for state in &mut self.states {
-            state.accept =
-                Self::calculate_accept(self.nfa.accept.clone(), state.nfa_states.clone());
+        let mut set = StateSet::new(derivatives.borrow().len());
+        let mut dfa = Dfa::default();
+        dfa.build_states(
+            starts,
+            |members| derivatives.borrow_mut().state_moves(members, &mut set),
+            |member| derivatives.borrow().accepts(member),
+            |member| Some(derivatives.borrow().rule(member)),
+            &nfa.accept,
+            (limits, started),
+        )?;
+        dfa.nfa = nfa;
+        Ok(dfa)
+    }
+    // the rules with the most distinct sets of their members among the dfa
+    // states, a rule like `(a|b)*a(a|b)(a|b)` has one for every dfa state,
+    // `owner` is the rule a member belongs to
+    fn growing_rules(
+        &self,
+        rules: &[nfa::Accept],
+        owner: impl Fn(usize) -> Option<usize>,
+    ) -> Vec<(Tag, Option<usize>)> {
+        let mut parts = HashSet::new();
+        for state in &self.states {
+            let mut members = state.nfa_states.clone();
+            members.sort_by_key(|&member| owner(member));
+            for part in members.chunk_by(|&a, &b| owner(a) == owner(b)) {
+                if let Some(rule) = owner(part[0]) {
+                    parts.insert((rule, part.to_vec()));
+                }
+            }
+        }
+        let mut counts = vec![0; rules.len()];
+        for (rule, _) in parts {
+            counts[rule] += 1;
         }
+        // rules blowing up together have similar counts, far above the others
+        let most = counts.iter().copied().max().unwrap_or(0);
+        let mut growing = (0..counts.len())
+            .filter(|&rule| counts[rule] > 1 && counts[rule] * 4 >= most)
+            .collect::<Vec<_>>();
+        growing.sort_by_key(|&rule| std::cmp::Reverse(counts[rule]));
+        growing
+            .into_iter()
+            .take(3)
+            .map(|rule| (rules[rule].tag.clone(), rules[rule].line))
+            .collect()
     }
-
-    pub fn minimize(&mut self) {
-        let mut partition = Vec::new();
-        let mut accepts = HashMap::new();
-        let mut reject = Vec::new();
-        for (i, state) in self.states.iter().enumerate() {
-            if let Some(accept_tag) = &state.accept {
-                let entry = accepts.entry(accept_tag).or_insert(Vec::new());
-                entry.push(i);
-            } else {
-                reject.push(i);
-            }
-        }
-        for (_, accept) in accepts {
-            partition.push(accept);
-        }
-        partition.push(reject);
-        loop {
-            let mut new_partition = Vec::new();
-            let mut changed = false;
-
-            for part in &partition {
-                let mut group_map: HashMap<Vec<(Transition, usize)>, Vec<usize>> = HashMap::new();
-                for &state_index in part {
-                    let transitions = self.states[state_index].transitions.clone();
-                    let group_transitions = transitions
-                        .iter()
-                        .map(|(t, next)| {
-                            (
-                                t.clone(),
-                                partition.iter().position(|p| p.contains(next)).unwrap(),
-                            )
-                        })
-                        .collect::<Vec<_>>();
-                    let mut found_superset = false;
-                    let mut superset_key = None;
-                    for (key, value) in &mut group_map {
-                        if group_transitions.iter().all(|item| key.contains(item)) {
-                            value.push(state_index);
-                            found_superset = true;
-                            break;
-                        } else if key.iter().all(|item| group_transitions.contains(item)) {
-                            superset_key = Some(key.clone());
-                            break;
-                        }
-                    }
-
-                    if let Some(key) = superset_key {
-                        if let Some(value) = group_map.remove(&key) {
-                            let mut new_value = value;
-                            new_value.push(state_index);
-                            group_map.insert(group_transitions, new_value);
-                        }
-                    } else if !found_superset {
-                        group_map.insert(group_transitions, vec![state_index]);
-                    }
+    pub fn construct_within(&mut self, limits: &Limits) -> Result<(), BuildError> {
+        self.construct_since(limits, Instant::now())
+    }
+    // `started` is when building the outermost dfa began, the dfas built for
+    // its `~r` and `r & s` count against the same time limit
+    fn construct_since(&mut self, limits: &Limits, started: Instant) -> Result<(), BuildError> {
+        let mut nfa = std::mem::take(&mut self.nfa);
+        nfa.construct_within(limits, started)?;
+        let built = self.subset_construction(&nfa, (limits, started), |_| true);
+        self.nfa = nfa;
+        built
+    }
+    // determinizes `nfa`, only following the chars that lead to sets of nfa
+    // states that `keep` accepts
+    fn subset_construction(
+        &mut self,
+        nfa: &Nfa,
+        limits: (&Limits, Instant),
+        keep: impl Fn(&[usize]) -> bool,
+    ) -> Result<(), BuildError> {
+        let closures = nfa.target_closures();
+        let mut set = StateSet::new(nfa.states.len());
+        let mut starts = Vec::new();
+        for &(start, bol_start) in &nfa.starts {
+            for start in [start, bol_start] {
+                nfa.add_epsilon_closure(start, &mut set);
+                starts.push(set.take());
+            }
+        }
+        let rule_of = nfa.rule_of();
+        self.build_states(
+            starts,
+            |states| {
+                let mut moves = Self::partition(nfa, states, &closures, &mut set);
+                moves.retain(|(_, _, next)| keep(next));
+                moves
+            },
+            |state| rule_of.get(&state).copied(),
+            |state| nfa.rule_owning(state),
+            &nfa.accept,
+            limits,
+        )
+    }
+    // subset construction over sets of members, which are nfa states or
+    // positions of the direct construction, `moves` splits the chars leaving a
+    // set by the set they lead to, `accepts` and `owner` give the rule a member
+    // accepts and the one it belongs to, `started` is when the time limit
+    // started counting
+    fn build_states(
+        &mut self,
+        starts: Vec<Vec<usize>>,
+        mut moves: impl FnMut(&[usize]) -> Vec<(char, char, Vec<usize>)>,
+        accepts: impl Fn(usize) -> Option<usize>,
+        owner: impl Fn(usize) -> Option<usize>,
+        rules: &[nfa::Accept],
+        (limits, started): (&Limits, Instant),
+    ) -> Result<(), BuildError> {
+        // the dfa state made of each set of members
+        let mut index = HashMap::new();
+        // looked up before inserting, so only new states copy their members
+        let mut add_state = |states: &mut Vec<DfaState>, nfa_states: Vec<usize>| {
+            if let Some(&state) = index.get(&nfa_states) {
+                return state;
+            }
+            index.insert(nfa_states.clone(), states.len());
+            states.push(DfaState {
+                transitions: Vec::new(),
+                nfa_states,
+                accept: None,
+                accept_eol: None,
+            });
+            states.len() - 1
+        };
+        for start in starts {
+            let start = add_state(&mut self.states, start);
+            self.starts.push(start);
+        }
+        // new states are appended, so every state is expanded once
+        let mut s = 0;
+        let mut transitions = 0;
+        while s < self.states.len() {
+            let ranges = moves(&self.states[s].nfa_states);
+            transitions += ranges.len();
+            for (lo, hi, next) in ranges {
+                let next = add_state(&mut self.states, next);
+                self.states[s].transitions.push((lo, hi, next));
+            }
+            s += 1;
+            let limit = match *limits {
+                Limits {
+                    states: Some(n), ..
+                } if self.states.len() > n => Limit::States(n),
+                Limits {
+                    transitions: Some(n),
+                    ..
+                } if transitions > n => Limit::Transitions(n),
+                Limits {
+                    time: Some(time), ..
+                } if started.elapsed() > time => Limit::Time(time),
+                _ => continue,
+            };
+            return Err(BuildError::TooLarge {
+                limit,
+                rules: self.growing_rules(rules, owner),
+            });
+        }
+        self.build_table();
+        for i in 0..self.states.len() {
+            let mut accepted = self.states[i]
+                .nfa_states
+                .iter()
+                .filter_map(|&member| accepts(member))
+                .collect::<Vec<_>>();
+            accepted.sort();
+            match Self::calculate_accept(rules, &accepted) {
+                Ok((accept, accept_eol)) => {
+                    self.states[i].accept = accept;
+                    self.states[i].accept_eol = accept_eol;
                 }
-
-                if group_map.len() > 1 {
-                    changed = true;
-                    for (_, group) in group_map {
-                        new_partition.push(group);
-                    }
-                } else {
-                    new_partition.push(part.clone());
+                Err((a, b)) => {
+                    let rule = |i: usize| (rules[i].tag.clone(), rules[i].line);
+                    let (a, b) = (a.min(b), a.max(b));
+                    return Err(BuildError::Ambiguous {
+                        rules: [rule(a), rule(b)],
+                        example: self.example(i),
+                    });
                 }
             }
+        }
+        Ok(())
+    }
 
-            if !changed {
-                break;
+    // Hopcroft's algorithm on the table completed with a dead state, states
+    // start out split by the rules they accept
+    pub fn minimize(&mut self) {
+        let n = self.states.len();
+        let classes = self.alphabet.len();
+        let dead = n;
+        let next = |state: usize, class: usize| match state {
+            _ if state == dead => dead,
+            _ => match self.table[state * classes + class] {
+                NO_STATE => dead,
+                next => next as usize,
+            },
+        };
+        // the states going to `t` on class `c` are
+        // `sources[offsets[c * (n + 1) + t]..offsets[c * (n + 1) + t + 1]]`
+        let mut offsets = vec![0; classes * (n + 1) + 1];
+        for state in 0..=n {
+            for class in 0..classes {
+                offsets[class * (n + 1) + next(state, class) + 1] += 1;
+            }
+        }
+        for i in 1..offsets.len() {
+            offsets[i] += offsets[i - 1];
+        }
+        let mut sources = vec![0; offsets[offsets.len() - 1]];
+        let mut filled = offsets.clone();
+        for state in 0..=n {
+            for class in 0..classes {
+                let slot = &mut filled[class * (n + 1) + next(state, class)];
+                sources[*slot] = state;
+                *slot += 1;
             }
-            partition = new_partition;
         }
 
-        partition.sort_by(|a, b| a[0].cmp(&b[0]));
-
-        let mut new_states = Vec::new();
-
-        for part in &partition {
-            let mut group_map: HashMap<Vec<(Transition, usize)>, Vec<usize>> = HashMap::new();
-            for &state_index in part {
-                let transitions = self.states[state_index].transitions.clone();
-                let group_transitions = transitions
-                    .iter()
-                    .map(|(t, next)| {
-                        (
-                            t.clone(),
-                            partition.iter().position(|p| p.contains(next)).unwrap(),
-                        )
-                    })
-                    .collect::<Vec<_>>();
-
-                let mut found_superset = false;
-                let mut superset_key = None;
-                for (key, value) in &mut group_map {
-                    if group_transitions.iter().all(|item| key.contains(item)) {
-                        value.push(state_index);
-                        found_superset = true;
-                        break;
-                    } else if key.iter().all(|item| group_transitions.contains(item)) {
-                        superset_key = Some(key.clone());
-                        break;
+        let keys = (0..n)
+            .map(|state| self.observable(state))
+            .chain([[None, None]]);
+        let mut partition = Partition::new(keys);
+        let mut pending = HashSet::new();
+        let mut stack = Vec::new();
+        for block in 0..partition.len() {
+            for class in 0..classes {
+                pending.insert((block, class));
+                stack.push((block, class));
+            }
+        }
+        while let Some((splitter, class)) = stack.pop() {
+            pending.remove(&(splitter, class));
+            let mut touched = Vec::new();
+            for target in partition.members(splitter).to_vec() {
+                let i = class * (n + 1) + target;
+                for &source in &sources[offsets[i]..offsets[i + 1]] {
+                    if let Some(block) = partition.mark(source) {
+                        touched.push(block);
                     }
                 }
-
-                if let Some(key) = superset_key {
-                    if let Some(value) = group_map.remove(&key) {
-                        let mut new_value = value;
-                        new_value.push(state_index);
-                        group_map.insert(group_transitions, new_value);
+            }
+            for (old, new) in partition.split(&touched) {
+                for class in 0..classes {
+                    let block = match pending.contains(&(old, class)) {
+                        true => new,
+                        false if partition.size(new) <= partition.size(old) => new,
+                        false => old,
+                    };
+                    if pending.insert((block, class)) {
+                        stack.push((block, class));
                     }
-                } else if !found_superset {
-                    group_map.insert(group_transitions, vec![state_index]);
                 }
             }
+        }
 
-            assert_eq!(group_map.len(), 1);
-            let (transitions, _) = group_map.into_iter().next().unwrap();
-
-            let mut accept = None;
-            for state_index in part {
-                if let Some(tag) = self.states[*state_index].accept.clone() {
-                    accept = Some(tag);
-                    break;
+        // states equivalent to the dead state are dropped, unless they start a mode
+        let dead_block = partition.block[dead];
+        let mut blocks = (0..partition.len())
+            .filter(|&b| b != dead_block || self.starts.iter().any(|&s| partition.block[s] == b))
+            .collect::<Vec<_>>();
+        blocks.sort_by_key(|&b| partition.members(b).iter().min().copied());
+        let mut index = vec![None; partition.len()];
+        for (i, &b) in blocks.iter().enumerate() {
+            index[b] = Some(i);
+        }
+        let mut new_states = Vec::new();
+        for &b in &blocks {
+            let state = &self.states[*partition.members(b).iter().min().unwrap()];
+            let mut transitions: Vec<(char, char, usize)> = Vec::new();
+            for &(lo, hi, next) in &state.transitions {
+                let Some(next) = index[partition.block[next]] else {
+                    continue;
+                };
+                match transitions.last_mut() {
+                    Some(last) if last.2 == next && next_char(last.1) == Some(lo) => last.1 = hi,
+                    _ => transitions.push((lo, hi, next)),
                 }
             }
-
             new_states.push(DfaState {
                 transitions,
-                nfa_states: self.states[part[0]].nfa_states.clone(),
-                accept,
+                nfa_states: state.nfa_states.clone(),
+                accept: state.accept,
+                accept_eol: state.accept_eol,
             });
         }
-
+        for start in &mut self.starts {
+            *start = index[partition.block[*start]].unwrap();
+        }
         self.states = new_states;
+        self.renumber();
+        self.build_table();
+    }
+    // numbers the states in the order a breadth-first search from the start
+    // states finds them, following each state's edges from the lowest char
+    fn renumber(&mut self) {
+        let mut index = vec![None; self.states.len()];
+        let mut order = Vec::new();
+        let mut visit = |state: usize, order: &mut Vec<usize>| {
+            *index[state].get_or_insert_with(|| {
+                order.push(state);
+                order.len() - 1
+            })
+        };
+        for start in &mut self.starts {
+            *start = visit(*start, &mut order);
+        }
+        let mut i = 0;
+        while i < order.len() {
+            for &(_, _, next) in &self.states[order[i]].transitions {
+                visit(next, &mut order);
+            }
+            i += 1;
+        }
+        let mut states = std::mem::take(&mut self.states)
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        self.states = order
+            .iter()
+            .map(|&old| {
+                let mut state = states[old].take().unwrap();
+                for (_, _, next) in &mut state.transitions {
+                    *next = index[*next].unwrap();
+                }
+                state
+            })
+            .collect();
     }
 
+    #[allow(dead_code)]
     pub fn to_graphviz(&self) -> String {
         let mut graph = DiGraph::<String, String>::new();
         let mut state_map = HashMap::new();
@@ -281,12 +991,23 @@ impl Dfa {
         for (i, state) in self.states.iter().enumerate() {
             let mut label = format!("State {}", i);
 
-            if state.nfa_states.contains(&self.nfa.start) {
-                label += " (start)";
+            for (mode, name) in self.modes().iter().enumerate() {
+                if i == self.start(mode, false) {
+                    label += &format!(" (start: {})", name);
+                }
+                if i == self.start(mode, true) && i != self.start(mode, false) {
+                    label += &format!(" (bol start: {})", name);
+                }
             }
 
-            if let Some(tag) = &state.accept {
-                label += &format!(" (accept: {})", tag.0);
+            if let Some(rule) = state.accept {
+                label += &format!(" (accept: {})", self.rule(rule).tag.0);
+            }
+            if let Some(rule) = state
+                .accept_eol
+                .filter(|_| state.accept_eol != state.accept)
+            {
+                label += &format!(" (accept at eol: {})", self.rule(rule).tag.0);
             }
 
             let node_index = graph.add_node(label);
@@ -294,42 +1015,120 @@ impl Dfa {
         }
 
         for (i, state) in self.states.iter().enumerate() {
-            for (transition, next) in &state.transitions {
-                let edge_label = match transition {
-                    Transition::Char(c) => c.to_string(),
+            for &(lo, hi, next) in &state.transitions {
+                let edge_label = match lo == hi {
+                    true => lo.to_string(),
+                    false => format!("{}-{}", lo, hi),
                 };
-                graph.add_edge(state_map[&i], state_map[next], edge_label);
+                graph.add_edge(state_map[&i], state_map[&next], edge_label);
             }
         }
 
-        let mut dot = Dot::new(&graph);
+        let dot = Dot::new(&graph);
         format!("{:?}", dot)
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
+    use crate::common::{Rule, StartCondition};
+    use crate::spec;
+
+    // a spec using modes, %prio, (?i:), ~, &, trailing context, $ and ^
+    pub fn feature_spec() -> LexSpec {
+        let rule = |pattern: &str, tag: &str| Rule::new(pattern.to_string(), Tag(tag.to_string()));
+        let mut bol = rule("^#[a-z]*", "DIRECTIVE");
+        bol.start_conditions = vec!["*".to_string()];
+        let mut prio = rule("if", "IF");
+        prio.priority = Some(1);
+        let mut string = rule("[^\"]*", "STRING");
+        string.start_conditions = vec!["STR".to_string()];
+        LexSpec {
+            start_conditions: vec![StartCondition {
+                name: "STR".to_string(),
+                exclusive: true,
+            }],
+            rules: vec![
+                rule("[a-z]+", "ID"),
+                prio,
+                rule("(?i:select)", "SELECT"),
+                rule("\"/*\"~(\\p{Any}*\"*/\"\\p{Any}*)\"*/\"", "COMMENT"),
+                rule("[a-z]+&~(.*e.*)", "NO_E"),
+                rule("[0-9]+/px", "LENGTH"),
+                rule("x*$", "XS"),
+                rule("(ab|a)?c+", "C"),
+                bol,
+                string,
+                rule("~(a*)", "NOT_AS"),
+            ],
+            ..LexSpec::default()
+        }
+    }
+
+    // checks that `build` gives the minimized dfa of thompson's construction
+    // for every c99 rule alone, the whole c99 spec and the feature spec
+    pub fn assert_same_as_thompson(build: impl Fn(LexSpec) -> Dfa) {
+        let both = |spec: LexSpec| {
+            let mut thompson = Dfa::new(spec.clone()).unwrap();
+            thompson.construct().unwrap();
+            thompson.minimize();
+            let mut other = build(spec);
+            other.minimize();
+            (thompson.shape(), other.shape())
+        };
+        for flags in [vec![], vec!["C11".to_string()]] {
+            let path = concat!(env!("CARGO_MANIFEST_DIR"), "/c99_modified.l");
+            let spec = spec::read_with_flags(path, &flags).unwrap();
+            for rule in &spec.rules {
+                let single = LexSpec {
+                    rules: vec![rule.clone()],
+                    ..spec.clone()
+                };
+                let (thompson, other) = both(single);
+                assert_eq!(thompson, other, "{} {:?}", rule.tag.0, rule.pattern);
+            }
+            let (thompson, other) = both(spec);
+            assert_eq!(thompson, other);
+        }
+        let (thompson, other) = both(feature_spec());
+        assert_eq!(thompson, other);
+    }
+
     #[test]
     fn test_get_epsilon_closure() {
         let pattern = vec![
             ("(a_b|a*b)*".to_string(), Tag("a".to_string())),
             ("(a|b)*abb".to_string(), Tag("b".to_string())),
         ];
-        let dfa = Dfa::new(pattern);
-        let mut closure = dfa.get_epsilon_closure(14);
-        closure.sort();
+        let mut dfa = Dfa::new(pattern).unwrap();
+        dfa.nfa.construct().unwrap();
+        let mut set = StateSet::new(dfa.nfa.states.len());
+        dfa.nfa.add_epsilon_closure(14, &mut set);
+        let closure = set.take();
         println!("{:?}", closure);
+        assert!(closure.contains(&14));
+        assert!(closure.windows(2).all(|w| w[0] < w[1]));
     }
     #[test]
-    fn test_compute_nfa_transition_range() {
+    fn test_partition() {
         let pattern = vec![
             ("(a_b|a*b)*".to_string(), Tag("a".to_string())),
             ("(a|b)*abb".to_string(), Tag("b".to_string())),
         ];
-        let dfa = Dfa::new(pattern);
-        let range = dfa.compute_nfa_transition_range(vec![0, 1, 2]);
+        let mut dfa = Dfa::new(pattern).unwrap();
+        dfa.construct().unwrap();
+        let start = dfa.states[dfa.start(0, false)].nfa_states.clone();
+        let mut set = StateSet::new(dfa.nfa.states.len());
+        let range = Dfa::partition(&dfa.nfa, &start, &dfa.nfa.target_closures(), &mut set);
         println!("{:?}", range);
+        assert_eq!(
+            range
+                .iter()
+                .map(|&(lo, hi, _)| (lo, hi))
+                .collect::<Vec<_>>(),
+            [('a', 'a'), ('b', 'b')]
+        );
     }
     #[test]
     fn test_construct() {
@@ -337,29 +1136,538 @@ mod tests {
             ("(a_b|a*b)*".to_string(), Tag("a".to_string())),
             ("(a|b)*abb".to_string(), Tag("b".to_string())),
         ];
-        let mut dfa = Dfa::new(pattern);
-        dfa.construct();
+        let mut dfa = Dfa::new(pattern).unwrap();
+        dfa.construct().unwrap();
         for (i, state) in dfa.states.iter().enumerate() {
             println!("State {}", i);
-            for (transition, next) in &state.transitions {
-                println!(
-                    "  {} -> State {}",
-                    match transition {
-                        Transition::Char(c) => c.to_string(),
-                    },
-                    next
+            for (lo, hi, next) in &state.transitions {
+                println!("  {}-{} -> State {}", lo, hi, next);
+            }
+        }
+    }
+    #[test]
+    fn test_range_transitions() {
+        let pattern = vec![
+            (
+                "[\\u{80}-\\u{10FFFF}]+".to_string(),
+                Tag("WIDE".to_string()),
+            ),
+            ("[\\0-\\x7f]".to_string(), Tag("ASCII".to_string())),
+            ("\\u{e000}".to_string(), Tag("PRIVATE".to_string())),
+        ];
+        let mut dfa = Dfa::new(pattern).unwrap();
+        dfa.construct().unwrap();
+        let edges = dfa
+            .states
+            .iter()
+            .map(|s| s.transitions.len())
+            .sum::<usize>();
+        assert!(edges < 20);
+        let start = dfa.start(0, false);
+        let next = |c: char| dfa.get_next_state(start, c);
+        assert_eq!(next('\u{10FFFF}'), next('\u{80}'));
+        assert_eq!(next('\u{d7ff}'), next('\u{80}'));
+        assert_ne!(next('\u{e000}'), next('\u{80}'));
+        assert_ne!(next('a'), next('\u{80}'));
+
+        // `[a-c]` and `[d-f]` lead to equivalent states, so one range is left
+        let pattern = vec![("[a-c]x|[d-f]x".to_string(), Tag("X".to_string()))];
+        let mut dfa = Dfa::new(pattern).unwrap();
+        dfa.construct().unwrap();
+        let start = dfa.start(0, false);
+        assert_eq!(dfa.edges(start).len(), 2);
+        dfa.minimize();
+        let start = dfa.start(0, false);
+        let edges = dfa.edges(start);
+        assert_eq!(
+            edges
+                .iter()
+                .map(|&(lo, hi, _)| (lo, hi))
+                .collect::<Vec<_>>(),
+            [('a', 'f')]
+        );
+    }
+    #[test]
+    fn test_limits() {
+        let rule = |pattern: String, tag: &str, line: usize| {
+            let mut rule = Rule::new(pattern, Tag(tag.to_string()));
+            rule.line = Some(line);
+            rule
+        };
+        // the dfa of A needs 2^21 states
+        let rules = vec![
+            rule("[a-z]+".to_string(), "ID", 1),
+            rule("(a|b)*a".to_string() + &"(a|b)".repeat(20), "A", 2),
+            rule("\\s".to_string(), "WS", 3),
+        ];
+        let limits = |states, transitions, time| Limits {
+            states,
+            transitions,
+            time,
+        };
+        let growing = vec![(Tag("A".to_string()), Some(2))];
+        for (limits, limit) in [
+            (limits(Some(1000), None, None), Limit::States(1000)),
+            (limits(None, Some(5000), None), Limit::Transitions(5000)),
+        ] {
+            let mut dfa = Dfa::new(rules.clone()).unwrap();
+            let e = dfa.construct_within(&limits).unwrap_err();
+            assert_eq!(
+                e,
+                BuildError::TooLarge {
+                    limit,
+                    rules: growing.clone()
+                }
+            );
+        }
+        // too few states are built to tell which rules grow
+        let e = Dfa::new(rules.clone())
+            .unwrap()
+            .construct_within(&limits(None, None, Some(Duration::ZERO)))
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            BuildError::TooLarge {
+                limit: Limit::Time(_),
+                ..
+            }
+        ));
+        let e = Dfa::new(rules.clone())
+            .unwrap()
+            .construct_within(&limits(Some(1000), None, None))
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "the dfa has more than 1000 states, mostly because of rule A (line 2)"
+        );
+        let rules = vec![
+            rule("(a|b)*a".to_string() + &"(a|b)".repeat(9), "A", 1),
+            rule("(a|b)*b".to_string() + &"(a|b)".repeat(9), "B", 2),
+            rule("[a-z]+".to_string(), "ID", 3),
+        ];
+        let e = Dfa::new(rules)
+            .unwrap()
+            .construct_within(&limits(Some(1000), None, None))
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "the dfa has more than 1000 states, mostly because of rules A (line 1), B (line 2)"
+        );
+        // specs within the limits build as before
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/c99_modified.l");
+        let mut dfa = Dfa::new(crate::spec::read_from_lex_file(path).unwrap()).unwrap();
+        let limits = limits(Some(5000), Some(100_000), Some(Duration::from_secs(60)));
+        dfa.construct_within(&limits).unwrap();
+    }
+    #[test]
+    fn test_construct_many_rules() {
+        // distinct words over [g-v]
+        let words = (0..500u32)
+            .map(|i| {
+                let digits = format!("{:x}", i.wrapping_mul(2654435761));
+                digits
+                    .chars()
+                    .map(|c| (b'g' + c.to_digit(16).unwrap() as u8) as char)
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>();
+        let mut pattern = words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| (word.clone(), Tag(format!("K{}", i))))
+            .collect::<Vec<_>>();
+        pattern.push(("[a-z]+".to_string(), Tag("ID".to_string())));
+        let mut dfa = Dfa::new(pattern).unwrap();
+        dfa.construct().unwrap();
+        let tag = |input: &str| {
+            let mut state = dfa.start(0, false);
+            for c in input.chars() {
+                state = dfa.get_next_state(state, c).unwrap();
+            }
+            dfa.states[state]
+                .accept
+                .map(|rule| dfa.rule(rule).tag.0.clone())
+        };
+        for (i, word) in words.iter().enumerate() {
+            assert_eq!(tag(word), Some(format!("K{}", i)));
+        }
+        assert_eq!(tag("abc"), Some("ID".to_string()));
+        // every set of nfa states becomes one dfa state
+        let mut sets = dfa.states.iter().map(|s| &s.nfa_states).collect::<Vec<_>>();
+        sets.sort();
+        sets.dedup();
+        assert_eq!(sets.len(), dfa.states.len());
+    }
+    // xorshift, the random tests see the same cases on every run
+    struct Rng(u64);
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+    fn random_pattern(rng: &mut Rng, depth: usize) -> String {
+        let choice = match depth {
+            0 => rng.below(4),
+            _ => rng.below(9),
+        };
+        let mut sub = || random_pattern(rng, depth - 1);
+        match choice {
+            0 => "a".to_string(),
+            1 => "b".to_string(),
+            2 => "c".to_string(),
+            3 => "[bc]".to_string(),
+            4 => format!("{}{}", sub(), sub()),
+            5 => format!("({}|{})", sub(), sub()),
+            6 => format!("({})*", sub()),
+            7 => format!("({})+", sub()),
+            _ => format!("({})?", sub()),
+        }
+    }
+    // the tags accepted after `input` from the start state, None once it gets stuck
+    fn run(dfa: &Dfa, bol: bool, input: &str) -> Option<(Option<String>, Option<String>)> {
+        let mut state = dfa.start(0, bol);
+        for c in input.chars() {
+            state = dfa.get_next_state(state, c)?;
+        }
+        let tag = |rule: Option<usize>| rule.map(|rule| dfa.rule(rule).tag.0.clone());
+        let accepts = (
+            tag(dfa.states[state].accept),
+            tag(dfa.states[state].accept_eol),
+        );
+        Some(accepts).filter(|accepts| *accepts != (None, None))
+    }
+    // the number of classes of equivalent states, found by filling the table
+    // of distinguishable pairs, with the missing transitions going to a dead state
+    fn equivalence_classes(dfa: &Dfa, chars: &[char]) -> usize {
+        let n = dfa.states.len() + 1;
+        let dead = n - 1;
+        let next = |s: usize, c: char| match s == dead {
+            true => dead,
+            false => dfa.get_next_state(s, c).unwrap_or(dead),
+        };
+        let accepts = |s: usize| match s == dead {
+            true => [None, None],
+            false => dfa.observable(s),
+        };
+        let mut distinct = (0..n)
+            .map(|p| (0..n).map(|q| accepts(p) != accepts(q)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for p in 0..n {
+                for q in 0..n {
+                    if !distinct[p][q] && chars.iter().any(|&c| distinct[next(p, c)][next(q, c)]) {
+                        distinct[p][q] = true;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        (0..n).filter(|&p| (0..p).all(|q| distinct[p][q])).count()
+    }
+    #[test]
+    fn test_minimize_random() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        let chars = ['a', 'b', 'c', 'd'];
+        let mut inputs = vec![String::new()];
+        for len in 1..=5 {
+            let shorter = inputs.clone();
+            for input in shorter.iter().filter(|input| input.len() == len - 1) {
+                inputs.extend(chars.iter().map(|c| format!("{}{}", input, c)));
+            }
+        }
+        for _ in 0..150 {
+            let rules = (0..1 + rng.below(4))
+                .map(|i| {
+                    let mut pattern = random_pattern(&mut rng, 3);
+                    match rng.below(6) {
+                        0 => pattern = format!("^{}", pattern),
+                        1 => pattern = format!("{}$", pattern),
+                        _ => {}
+                    }
+                    // some rules share a tag
+                    (pattern, Tag(format!("R{}", i % 3)))
+                })
+                .collect::<Vec<_>>();
+            let mut dfa = Dfa::new(rules.clone()).unwrap();
+            dfa.construct().unwrap();
+            let classes = equivalence_classes(&dfa, &chars);
+            let mut minimized = Dfa::new(rules.clone()).unwrap();
+            minimized.construct().unwrap();
+            minimized.minimize();
+            for input in &inputs {
+                for bol in [false, true] {
+                    assert_eq!(
+                        run(&dfa, bol, input),
+                        run(&minimized, bol, input),
+                        "{:?} on {:?}",
+                        rules,
+                        input
+                    );
+                }
+            }
+            // only the dead state's class is dropped, unless a start is in it
+            let dead_start = (0..2).any(|bol| {
+                run(&minimized, bol == 1, "").is_none()
+                    && minimized.edges(minimized.start(0, bol == 1)).is_empty()
+            });
+            let expected = classes - 1 + dead_start as usize;
+            assert_eq!(minimized.states.len(), expected, "{:?}", rules);
+        }
+    }
+    #[test]
+    fn test_canonical_numbering() {
+        let pattern = vec![
+            ("ab|ac".to_string(), Tag("A".to_string())),
+            ("b+".to_string(), Tag("B".to_string())),
+            ("^c".to_string(), Tag("C".to_string())),
+        ];
+        let mut dfa = Dfa::new(pattern).unwrap();
+        dfa.construct().unwrap();
+        dfa.minimize();
+        assert_eq!(dfa.starts, [0, 1]);
+        let states = dfa
+            .states
+            .iter()
+            .map(|state| (state.transitions.clone(), state.accept))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            [
+                (vec![('a', 'a', 2), ('b', 'b', 3)], None),
+                (vec![('a', 'a', 2), ('b', 'b', 3), ('c', 'c', 4)], None),
+                (vec![('b', 'c', 5)], None),
+                (vec![('b', 'b', 3)], Some(1)),
+                (vec![], Some(2)),
+                (vec![], Some(0)),
+            ]
+        );
+        // the same spec gives the same table and graph every time
+        let (a, b) = (c99(), c99());
+        assert_eq!(a.table, b.table);
+        assert_eq!(a.to_graphviz(), b.to_graphviz());
+    }
+    fn c99() -> Dfa {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/c99_modified.l");
+        let mut dfa = Dfa::new(crate::spec::read_from_lex_file(path).unwrap()).unwrap();
+        dfa.construct().unwrap();
+        dfa.minimize();
+        dfa
+    }
+    #[test]
+    fn test_table() {
+        let dfa = c99();
+        assert!(dfa.alphabet.len() < 128);
+        let chars = ('\0'..='\u{250}').chain(['正', '\u{d7ff}', '\u{e000}', char::MAX]);
+        for c in chars {
+            for state in 0..dfa.states.len() {
+                assert_eq!(
+                    dfa.get_next_state(state, c),
+                    dfa.next_state_by_ranges(state, c)
                 );
             }
         }
     }
+    // the number of tokens in `input` by longest match, taking `step` for transitions
+    fn count_tokens(
+        dfa: &Dfa,
+        input: &[char],
+        step: impl Fn(usize, char) -> Option<usize>,
+    ) -> usize {
+        let (mut pos, mut tokens) = (0, 0);
+        while pos < input.len() {
+            let mut state = dfa.start(0, false);
+            let mut end = pos + 1;
+            for (i, &c) in input[pos..].iter().enumerate() {
+                match step(state, c) {
+                    Some(next) => state = next,
+                    None => break,
+                }
+                if dfa.states[state].accept.is_some() {
+                    end = pos + i + 1;
+                }
+            }
+            pos = end;
+            tokens += 1;
+        }
+        tokens
+    }
+    // cargo test --release bench_next_state -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_next_state() {
+        let dfa = c99();
+        let unit = "static unsigned long hash(const char *s, int n) {\n    unsigned long h = 0x1505UL;\n    for (int i = 0; i < n && s[i] != '\\0'; ++i) {\n        h = ((h << 5) + h) ^ (unsigned char)s[i]; /* djb2 */\n    }\n    return h % 1000003 + 1.5e+3f;\n}\n";
+        let input = unit.repeat(16 * 1024).chars().collect::<Vec<_>>();
+        let mb = input.len() as f64 / (1 << 20) as f64;
+        let time = |name: &str, step: &dyn Fn(usize, char) -> Option<usize>| {
+            let start = std::time::Instant::now();
+            let tokens = count_tokens(&dfa, &input, step);
+            let secs = start.elapsed().as_secs_f64();
+            println!("{}: {} tokens, {:.1} MB/s", name, tokens, mb / secs);
+            tokens
+        };
+        let table = time("table", &|state, c| dfa.get_next_state(state, c));
+        let ranges = time("ranges", &|state, c| dfa.next_state_by_ranges(state, c));
+        // the scan over the transitions that the table replaced
+        let linear = time("linear", &|state, c| {
+            dfa.states[state]
+                .transitions
+                .iter()
+                .find(|&&(lo, hi, _)| lo <= c && c <= hi)
+                .map(|&(_, _, next)| next)
+        });
+        assert_eq!(table, ranges);
+        assert_eq!(table, linear);
+    }
+    // cargo test --release bench_construction -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_construction() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/c99_modified.l");
+        let spec = crate::spec::read_from_lex_file(path).unwrap();
+        // the fastest of a few runs, the first one also builds the unicode tables
+        let time = |name: &str, build: &dyn Fn() -> Dfa| {
+            let mut best = None;
+            for _ in 0..5 {
+                let start = std::time::Instant::now();
+                let mut dfa = build();
+                let built = start.elapsed();
+                let states = dfa.states.len();
+                dfa.minimize();
+                let run = (start.elapsed(), built, states, dfa.states.len());
+                best = Some(best.map_or(run, |best| run.min(best)));
+            }
+            let (total, built, states, minimized) = best.unwrap();
+            println!(
+                "{}: {} states in {:?}, {} after minimizing in {:?}",
+                name, states, built, minimized, total
+            );
+        };
+        time("thompson", &|| {
+            let mut dfa = Dfa::new(spec.clone()).unwrap();
+            dfa.construct().unwrap();
+            dfa
+        });
+        time("followpos", &|| {
+            Dfa::from_followpos(spec.clone(), &Limits::default()).unwrap()
+        });
+        time("derivatives", &|| {
+            Dfa::from_derivatives(spec.clone(), &Limits::default()).unwrap()
+        });
+    }
+    fn accepts(dfa: &Dfa, input: &str) -> bool {
+        let mut state = 0;
+        for c in input.chars() {
+            match dfa.get_next_state(state, c) {
+                Some(next) => state = next,
+                None => return false,
+            }
+        }
+        dfa.states[state].accept.is_some()
+    }
+    #[test]
+    fn test_complement() {
+        let ast = crate::ast::Parser::new("ab*".to_string()).parse().unwrap();
+        let dfa = Dfa::from_ast(ast, &Limits::default(), Instant::now())
+            .unwrap()
+            .complement();
+        assert!(!accepts(&dfa, "a"));
+        assert!(!accepts(&dfa, "abbb"));
+        assert!(accepts(&dfa, ""));
+        assert!(accepts(&dfa, "b"));
+        assert!(accepts(&dfa, "aba"));
+        assert!(accepts(&dfa, "正"));
+    }
+    #[test]
+    fn test_intersect() {
+        let left = crate::ast::Parser::new("[a-z]+".to_string())
+            .parse()
+            .unwrap();
+        let right = crate::ast::Parser::new("\\p{Any}*(ab|c)".to_string())
+            .parse()
+            .unwrap();
+        let build = |ast| Dfa::from_ast(ast, &Limits::default(), Instant::now()).unwrap();
+        let dfa = build(left).intersect(&build(right));
+        assert!(accepts(&dfa, "xab"));
+        assert!(accepts(&dfa, "c"));
+        assert!(!accepts(&dfa, "1ab"));
+        assert!(!accepts(&dfa, "abx"));
+        assert!(!accepts(&dfa, ""));
+    }
+    #[test]
+    fn test_priority() {
+        let rule = |pattern: &str, tag: &str, priority: Option<i32>, line: usize| {
+            let mut rule = Rule::new(pattern.to_string(), Tag(tag.to_string()));
+            rule.priority = priority;
+            rule.line = Some(line);
+            rule
+        };
+        let accepted = |rules: Vec<Rule>, input: &str| {
+            let mut dfa = Dfa::new(rules).unwrap();
+            dfa.construct().unwrap();
+            let mut state = dfa.start(0, false);
+            for c in input.chars() {
+                state = dfa.get_next_state(state, c).unwrap();
+            }
+            dfa.rule(dfa.states[state].accept.unwrap()).tag.0.clone()
+        };
+        let rules = vec![
+            rule("[a-z]+", "ID", None, 1),
+            rule("if", "IF", Some(1), 2),
+            rule("i[a-z]", "I2", Some(-1), 3),
+        ];
+        assert_eq!(accepted(rules.clone(), "if"), "IF");
+        assert_eq!(accepted(rules.clone(), "in"), "ID");
+        assert_eq!(accepted(rules, "x"), "ID");
+        // without priorities the first rule still wins
+        let rules = vec![rule("[a-z]+", "ID", None, 1), rule("if", "IF", None, 2)];
+        assert_eq!(accepted(rules, "if"), "ID");
+
+        let rules = vec![
+            rule("[a-z]+", "ID", Some(1), 1),
+            rule("x|y", "XY", None, 2),
+            rule("[0-9]*y", "Y", Some(1), 3),
+        ];
+        let spec = LexSpec::from(rules.clone());
+        let mut dfa = Dfa::new(rules).unwrap();
+        let e = dfa.construct().unwrap_err();
+        assert_eq!(Dfa::check_priorities(&spec).unwrap_err(), e);
+        assert_eq!(
+            e,
+            BuildError::Ambiguous {
+                rules: [
+                    (Tag("ID".to_string()), Some(1)),
+                    (Tag("Y".to_string()), Some(3))
+                ],
+                example: "y".to_string(),
+            }
+        );
+        assert_eq!(
+            e.to_string(),
+            "rules ID (line 1) and Y (line 3) have the same priority and both match \"y\""
+        );
+        // a rule with a higher priority settles the tie
+        let rules = vec![
+            rule("[a-z]+", "ID", Some(1), 1),
+            rule("y", "YY", Some(2), 2),
+            rule("[0-9]*y", "Y", Some(1), 3),
+        ];
+        assert!(Dfa::check_priorities(&LexSpec::from(rules.clone())).is_ok());
+        assert_eq!(accepted(rules, "y"), "YY");
+    }
     #[test]
     fn test_dfa_graphviz() {
         let pattern = vec![
             ("(a|b)*c".to_string(), Tag("A正则".to_string())),
             ("(a|b)*abb".to_string(), Tag("B正则".to_string())),
         ];
-        let mut dfa = Dfa::new(pattern);
-        dfa.construct();
+        let mut dfa = Dfa::new(pattern).unwrap();
+        dfa.construct().unwrap();
         let dot = dfa.to_graphviz();
         println!("{}", dot);
     }
@@ -370,20 +1678,35 @@ mod tests {
             ("(a|b)*c".to_string(), Tag("A正则".to_string())),
             ("(a|b)*abb".to_string(), Tag("B正则".to_string())),
         ];
-        let mut dfa = Dfa::new(pattern);
-        dfa.construct();
+        let mut dfa = Dfa::new(pattern).unwrap();
+        dfa.construct().unwrap();
         dfa.minimize();
         let dot = dfa.to_graphviz();
         println!("{}", dot);
     }
     #[test]
+    fn test_minimize_same_tag() {
+        // the accepts of rules with the same tag and attributes are merged
+        let rule = |pattern: &str, tag: &str| Rule::new(pattern.to_string(), Tag(tag.to_string()));
+        let mut dfa = Dfa::new(vec![rule("ab", "X"), rule("cb", "X")]).unwrap();
+        dfa.construct().unwrap();
+        dfa.minimize();
+        assert_eq!(dfa.states.len(), 3);
+        let mut begin = rule("cb", "X");
+        begin.mode_action = Some(crate::common::ModeAction::Pop);
+        let mut dfa = Dfa::new(vec![rule("ab", "X"), begin]).unwrap();
+        dfa.construct().unwrap();
+        dfa.minimize();
+        assert_eq!(dfa.states.len(), 5);
+    }
+    #[test]
     fn test_minimize2() {
         let pattern = vec![
             ("abcd".to_string(), Tag("SHORT".to_string())),
             ("abcdefg".to_string(), Tag("LONG".to_string())),
         ];
-        let mut dfa = Dfa::new(pattern);
-        dfa.construct();
+        let mut dfa = Dfa::new(pattern).unwrap();
+        dfa.construct().unwrap();
         dfa.minimize();
         let dot = dfa.to_graphviz();
         println!("{}", dot);
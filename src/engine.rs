@@ -0,0 +1,56 @@
+use crate::dfa::Dfa;
+use crate::nfa;
+
+// what the lexer runs a token through, `reset` starts a token and `step`
+// feeds it one char at a time until no rule can match any longer
+pub trait Engine {
+    fn reset(&mut self, mode: usize, bol: bool);
+    // returns false when no rule matches the chars stepped so far or any longer string
+    fn step(&mut self, c: char) -> bool;
+    // the rule accepting the chars stepped so far
+    fn accept(&self) -> Option<usize>;
+    // the rule to use instead of `accept` when a newline or the end of input follows
+    fn accept_eol(&self) -> Option<usize>;
+    fn rule(&self, rule: usize) -> &nfa::Accept;
+    // mode names, INITIAL first
+    fn modes(&self) -> &[String];
+}
+
+// runs a DFA built up front
+pub struct DfaEngine {
+    dfa: Dfa,
+    state: usize,
+}
+
+impl DfaEngine {
+    pub fn new(dfa: Dfa) -> Self {
+        DfaEngine { dfa, state: 0 }
+    }
+}
+
+impl Engine for DfaEngine {
+    fn reset(&mut self, mode: usize, bol: bool) {
+        self.state = self.dfa.start(mode, bol);
+    }
+    fn step(&mut self, c: char) -> bool {
+        match self.dfa.get_next_state(self.state, c) {
+            Some(next) => {
+                self.state = next;
+                true
+            }
+            None => false,
+        }
+    }
+    fn accept(&self) -> Option<usize> {
+        self.dfa.states[self.state].accept
+    }
+    fn accept_eol(&self) -> Option<usize> {
+        self.dfa.states[self.state].accept_eol
+    }
+    fn rule(&self, rule: usize) -> &nfa::Accept {
+        self.dfa.rule(rule)
+    }
+    fn modes(&self) -> &[String] {
+        self.dfa.modes()
+    }
+}
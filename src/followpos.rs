@@ -0,0 +1,256 @@
+use crate::ast::AstNode;
+use crate::class::CharClass;
+use crate::dfa::{self, BuildError, Dfa, Limits};
+use crate::nfa::StateSet;
+use std::collections::HashMap;
+use std::time::Instant;
+
+// a leaf of the augmented regex, the `#` after every rule is the end marker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Symbol {
+    Chars(Vec<(char, char)>),
+    End(usize),
+}
+
+// nullable, firstpos and lastpos of a node
+struct Node {
+    nullable: bool,
+    first: Vec<usize>,
+    last: Vec<usize>,
+}
+
+// the positions of the rules for the direct construction of the dragon book,
+// a dfa state is the set of positions that can match the next char
+#[derive(Debug, Default)]
+pub struct Positions {
+    pub symbols: Vec<Symbol>,
+    pub follow: Vec<Vec<usize>>,
+    // the rule every position belongs to
+    pub rules: Vec<usize>,
+}
+
+fn union(mut a: Vec<usize>, b: &[usize]) -> Vec<usize> {
+    a.extend_from_slice(b);
+    a.sort_unstable();
+    a.dedup();
+    a
+}
+
+impl Positions {
+    // adds `ast` followed by the end marker of `rule` and returns its firstpos
+    pub fn add_rule(
+        &mut self,
+        rule: usize,
+        ast: &AstNode,
+        limits: &Limits,
+        started: Instant,
+    ) -> Result<Vec<usize>, BuildError> {
+        let node = self.visit(ast, false, rule, limits, started)?;
+        let end = self.leaf(Symbol::End(rule), rule);
+        for &p in &node.last {
+            self.follow[p].push(end);
+        }
+        Ok(match node.nullable {
+            true => union(node.first, &[end]),
+            false => node.first,
+        })
+    }
+    // sorts the followpos sets once every rule is added
+    pub fn finish(&mut self) {
+        for follow in &mut self.follow {
+            follow.sort_unstable();
+            follow.dedup();
+        }
+    }
+    pub fn accepts(&self, position: usize) -> Option<usize> {
+        match self.symbols[position] {
+            Symbol::End(rule) => Some(rule),
+            Symbol::Chars(_) => None,
+        }
+    }
+    // the chars leaving the positions of a dfa state, split by the union of
+    // followpos of the positions that match them
+    pub fn moves(&self, positions: &[usize], set: &mut StateSet) -> Vec<(char, char, Vec<usize>)> {
+        let mut events = Vec::new();
+        for &p in positions {
+            if let Symbol::Chars(ranges) = &self.symbols[p] {
+                for &(lo, hi) in ranges {
+                    events.push((lo as u32, true, p));
+                    events.push((hi as u32 + 1, false, p));
+                }
+            }
+        }
+        dfa::split_ranges(events, |p| self.follow[p].iter().copied(), set)
+    }
+    fn leaf(&mut self, symbol: Symbol, rule: usize) -> usize {
+        self.symbols.push(symbol);
+        self.follow.push(Vec::new());
+        self.rules.push(rule);
+        self.symbols.len() - 1
+    }
+    fn chars(&mut self, class: CharClass, ignore_case: bool, rule: usize) -> Node {
+        let class = match ignore_case {
+            true => class.case_fold(),
+            false => class,
+        };
+        let p = self.leaf(Symbol::Chars(class.ranges().to_vec()), rule);
+        Node {
+            nullable: false,
+            first: vec![p],
+            last: vec![p],
+        }
+    }
+    // `~r` and `r & s` have no followpos rules, their dfa is added instead
+    // with a position for the transitions between every two of its states
+    fn sub_dfa(&mut self, dfa: &Dfa, rule: usize) -> Node {
+        let live = dfa.live_states();
+        let mut index = HashMap::new();
+        // the positions leaving every state, with the state they lead to
+        let mut moves = vec![Vec::new(); dfa.states.len()];
+        for (s, moves_from) in moves.iter_mut().enumerate() {
+            for &(lo, hi, t) in dfa.edges(s) {
+                if !live[s] || !live[t] {
+                    continue;
+                }
+                let p = *index.entry((s, t)).or_insert_with(|| {
+                    let p = self.leaf(Symbol::Chars(Vec::new()), rule);
+                    moves_from.push((p, t));
+                    p
+                });
+                if let Symbol::Chars(ranges) = &mut self.symbols[p] {
+                    ranges.push((lo, hi));
+                }
+            }
+        }
+        let mut last = Vec::new();
+        for moves_from in &moves {
+            for &(p, t) in moves_from {
+                self.follow[p].extend(moves[t].iter().map(|&(q, _)| q));
+                if dfa.states[t].accept.is_some() {
+                    last.push(p);
+                }
+            }
+        }
+        last.sort_unstable();
+        Node {
+            nullable: dfa.states[0].accept.is_some(),
+            first: moves[0].iter().map(|&(p, _)| p).collect(),
+            last,
+        }
+    }
+    fn visit(
+        &mut self,
+        node: &AstNode,
+        ignore_case: bool,
+        rule: usize,
+        limits: &Limits,
+        started: Instant,
+    ) -> Result<Node, BuildError> {
+        let with_case = |node: &AstNode| match ignore_case {
+            true => AstNode::IgnoreCase(Box::new(node.clone())),
+            false => node.clone(),
+        };
+        let node = match node {
+            AstNode::Char(c) => self.chars(CharClass::from_range(*c, *c), ignore_case, rule),
+            AstNode::Class(class) => self.chars(class.clone(), ignore_case, rule),
+            AstNode::IgnoreCase(node) => self.visit(node, true, rule, limits, started)?,
+            AstNode::Complement(node) => {
+                let dfa = Dfa::from_ast(with_case(node), limits, started)?.complement();
+                self.sub_dfa(&dfa, rule)
+            }
+            AstNode::Intersect(left, right) => {
+                let left = Dfa::from_ast(with_case(left), limits, started)?;
+                let right = Dfa::from_ast(with_case(right), limits, started)?;
+                self.sub_dfa(&left.intersect(&right), rule)
+            }
+            AstNode::And(left, right) => {
+                let left = self.visit(left, ignore_case, rule, limits, started)?;
+                let right = self.visit(right, ignore_case, rule, limits, started)?;
+                for &p in &left.last {
+                    self.follow[p].extend_from_slice(&right.first);
+                }
+                Node {
+                    nullable: left.nullable && right.nullable,
+                    first: match left.nullable {
+                        true => union(left.first, &right.first),
+                        false => left.first,
+                    },
+                    last: match right.nullable {
+                        true => union(right.last, &left.last),
+                        false => right.last,
+                    },
+                }
+            }
+            AstNode::Or(left, right) => {
+                let left = self.visit(left, ignore_case, rule, limits, started)?;
+                let right = self.visit(right, ignore_case, rule, limits, started)?;
+                Node {
+                    nullable: left.nullable || right.nullable,
+                    first: union(left.first, &right.first),
+                    last: union(left.last, &right.last),
+                }
+            }
+            AstNode::Star(inner) | AstNode::Plus(inner) => {
+                let inner = self.visit(inner, ignore_case, rule, limits, started)?;
+                for &p in &inner.last {
+                    self.follow[p].extend_from_slice(&inner.first);
+                }
+                Node {
+                    nullable: inner.nullable || matches!(node, AstNode::Star(_)),
+                    ..inner
+                }
+            }
+            AstNode::Question(node) => {
+                let node = self.visit(node, ignore_case, rule, limits, started)?;
+                Node {
+                    nullable: true,
+                    ..node
+                }
+            }
+        };
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{LexSpec, Tag};
+    use crate::dfa::tests::assert_same_as_thompson;
+
+    #[test]
+    fn test_followpos() {
+        // the example of the dragon book with positions counted from 0
+        let ast = crate::ast::Parser::new("(a|b)*abb".to_string())
+            .parse_pattern()
+            .unwrap()
+            .ast;
+        let mut positions = Positions::default();
+        let first = positions
+            .add_rule(0, &ast, &Limits::default(), Instant::now())
+            .unwrap();
+        positions.finish();
+        assert_eq!(first, [0, 1, 2]);
+        assert_eq!(
+            positions.follow,
+            [
+                vec![0, 1, 2],
+                vec![0, 1, 2],
+                vec![3],
+                vec![4],
+                vec![5],
+                vec![]
+            ]
+        );
+        assert_eq!(positions.symbols[5], Symbol::End(0));
+        let spec = LexSpec::from(vec![("(a|b)*abb".to_string(), Tag("ABB".to_string()))]);
+        let dfa = Dfa::from_followpos(spec, &Limits::default()).unwrap();
+        // the four states of the dragon book
+        assert_eq!(dfa.states.len(), 4);
+    }
+
+    #[test]
+    fn test_against_thompson() {
+        assert_same_as_thompson(|spec| Dfa::from_followpos(spec, &Limits::default()).unwrap());
+    }
+}
@@ -0,0 +1,313 @@
+use crate::alphabet::Alphabet;
+use crate::common::LexSpec;
+use crate::dfa::{BuildError, Dfa};
+use crate::engine::Engine;
+use crate::nfa::{self, Nfa, StateSet};
+use std::collections::HashMap;
+
+pub const DEFAULT_CACHE_SIZE: usize = 2 << 20;
+// a transition that hasn't been followed since the state was cached
+const UNKNOWN: u32 = u32::MAX;
+const DEAD: u32 = u32::MAX - 1;
+// bytes counted for a cached state besides its row and its nfa states
+const STATE_OVERHEAD: usize = 64;
+// a clear comes too soon when fewer chars than this per cached state were
+// stepped since the previous one, after enough of those the cache is given up
+const MIN_STEPS_PER_STATE: usize = 10;
+const MAX_THRASHING_CLEARS: usize = 3;
+
+struct CachedState {
+    nfa_states: Vec<usize>,
+    accept: Option<usize>,
+    accept_eol: Option<usize>,
+}
+
+enum Position {
+    Cached(u32),
+    // the states of the nfa simulation once the cache is given up
+    Nfa {
+        states: Vec<usize>,
+        accept: Option<usize>,
+        accept_eol: Option<usize>,
+    },
+}
+
+// builds dfa states from the nfa as the input reaches them and caches them
+// up to `cache_size` bytes, the whole cache is dropped when it fills up
+pub struct LazyDfa {
+    nfa: Nfa,
+    closures: HashMap<usize, Vec<usize>>,
+    rule_of: HashMap<usize, usize>,
+    // the closure of every start state, in the order of `Dfa::start`
+    start_sets: Vec<Vec<usize>>,
+    alphabet: Alphabet,
+    set: StateSet,
+    states: Vec<CachedState>,
+    index: HashMap<Vec<usize>, u32>,
+    // a row of `alphabet.len()` transitions for every cached state
+    table: Vec<u32>,
+    // the cached start states, UNKNOWN when not cached
+    starts: Vec<u32>,
+    cache_size: usize,
+    used: usize,
+    // chars stepped since the last clear
+    steps: usize,
+    clears: usize,
+    thrashing_clears: usize,
+    position: Position,
+}
+
+impl LazyDfa {
+    pub fn new<S: Into<LexSpec>>(spec: S, cache_size: usize) -> Result<LazyDfa, BuildError> {
+        let spec = spec.into();
+        let mut nfa = Nfa::new(spec.clone())?;
+        Dfa::check_priorities(&spec)?;
+        nfa.construct()?;
+        let mut set = StateSet::new(nfa.states.len());
+        let mut start_sets = Vec::new();
+        for &(start, bol_start) in &nfa.starts {
+            for start in [start, bol_start] {
+                nfa.add_epsilon_closure(start, &mut set);
+                start_sets.push(set.take());
+            }
+        }
+        let ranges = nfa.states.iter().flat_map(|state| {
+            state.transitions.iter().filter_map(|(t, _)| match t {
+                nfa::Transition::Range(lo, hi) => Some((*lo, *hi)),
+                nfa::Transition::Epsilon => None,
+            })
+        });
+        Ok(LazyDfa {
+            alphabet: Alphabet::intervals(ranges),
+            closures: nfa.target_closures(),
+            rule_of: nfa.rule_of(),
+            starts: vec![UNKNOWN; start_sets.len()],
+            start_sets,
+            set,
+            nfa,
+            states: Vec::new(),
+            index: HashMap::new(),
+            table: Vec::new(),
+            cache_size,
+            used: 0,
+            steps: 0,
+            clears: 0,
+            thrashing_clears: 0,
+            position: Position::Cached(0),
+        })
+    }
+    // whether the cache was given up for simulating the nfa
+    pub fn is_simulating(&self) -> bool {
+        self.thrashing_clears >= MAX_THRASHING_CLEARS
+    }
+    fn accepts(&self, nfa_states: &[usize]) -> (Option<usize>, Option<usize>) {
+        let mut rules = nfa_states
+            .iter()
+            .filter_map(|state| self.rule_of.get(state).copied())
+            .collect::<Vec<_>>();
+        rules.sort();
+        (
+            Dfa::winning_rule(&self.nfa.accept, &rules, false),
+            Dfa::winning_rule(&self.nfa.accept, &rules, true),
+        )
+    }
+    // the closure of the states `c` leads to from `nfa_states`
+    fn next_states(&mut self, nfa_states: &[usize], c: char) -> Vec<usize> {
+        for &s in nfa_states {
+            for (t, next) in &self.nfa.states[s].transitions {
+                if let nfa::Transition::Range(lo, hi) = *t {
+                    if lo <= c && c <= hi {
+                        for &s in &self.closures[next] {
+                            self.set.insert(s);
+                        }
+                    }
+                }
+            }
+        }
+        self.set.take()
+    }
+    fn clear(&mut self) {
+        if self.steps < MIN_STEPS_PER_STATE * self.states.len() {
+            self.thrashing_clears += 1;
+        }
+        self.clears += 1;
+        self.steps = 0;
+        self.used = 0;
+        self.states.clear();
+        self.index.clear();
+        self.table.clear();
+        self.starts.fill(UNKNOWN);
+    }
+    // the cached state made of `nfa_states`, adding it may clear the cache
+    fn add_state(&mut self, nfa_states: Vec<usize>) -> u32 {
+        if let Some(&state) = self.index.get(&nfa_states) {
+            return state;
+        }
+        let size = STATE_OVERHEAD + 4 * self.alphabet.len() + 16 * nfa_states.len();
+        if self.used + size > self.cache_size && !self.states.is_empty() {
+            self.clear();
+        }
+        self.used += size;
+        let (accept, accept_eol) = self.accepts(&nfa_states);
+        let state = self.states.len() as u32;
+        self.index.insert(nfa_states.clone(), state);
+        self.states.push(CachedState {
+            nfa_states,
+            accept,
+            accept_eol,
+        });
+        let row = self.table.len();
+        self.table.resize(row + self.alphabet.len(), UNKNOWN);
+        state
+    }
+    fn simulate(&self, states: Vec<usize>) -> Position {
+        let (accept, accept_eol) = self.accepts(&states);
+        Position::Nfa {
+            states,
+            accept,
+            accept_eol,
+        }
+    }
+}
+
+impl Engine for LazyDfa {
+    fn reset(&mut self, mode: usize, bol: bool) {
+        let start = 2 * mode + bol as usize;
+        if self.is_simulating() {
+            self.position = self.simulate(self.start_sets[start].clone());
+            return;
+        }
+        if self.starts[start] == UNKNOWN {
+            let state = self.add_state(self.start_sets[start].clone());
+            self.starts[start] = state;
+        }
+        self.position = Position::Cached(self.starts[start]);
+    }
+    fn step(&mut self, c: char) -> bool {
+        self.steps += 1;
+        let state = match &mut self.position {
+            Position::Cached(state) => *state as usize,
+            Position::Nfa { states, .. } => {
+                let states = std::mem::take(states);
+                let next = self.next_states(&states, c);
+                if next.is_empty() {
+                    return false;
+                }
+                self.position = self.simulate(next);
+                return true;
+            }
+        };
+        let slot = state * self.alphabet.len() + self.alphabet.class(c);
+        let next = match self.table[slot] {
+            DEAD => return false,
+            UNKNOWN => {
+                let nfa_states = std::mem::take(&mut self.states[state].nfa_states);
+                let next = self.next_states(&nfa_states, c);
+                self.states[state].nfa_states = nfa_states;
+                if next.is_empty() {
+                    self.table[slot] = DEAD;
+                    return false;
+                }
+                let clears = self.clears;
+                let next = self.add_state(next);
+                if self.is_simulating() {
+                    let states = self.states[next as usize].nfa_states.clone();
+                    self.position = self.simulate(states);
+                    return true;
+                }
+                // the row of `state` is gone when the cache was cleared
+                if self.clears == clears {
+                    self.table[slot] = next;
+                }
+                next
+            }
+            next => next,
+        };
+        self.position = Position::Cached(next);
+        true
+    }
+    fn accept(&self) -> Option<usize> {
+        match &self.position {
+            Position::Cached(state) => self.states[*state as usize].accept,
+            Position::Nfa { accept, .. } => *accept,
+        }
+    }
+    fn accept_eol(&self) -> Option<usize> {
+        match &self.position {
+            Position::Cached(state) => self.states[*state as usize].accept_eol,
+            Position::Nfa { accept_eol, .. } => *accept_eol,
+        }
+    }
+    fn rule(&self, rule: usize) -> &nfa::Accept {
+        &self.nfa.accept[rule]
+    }
+    fn modes(&self) -> &[String] {
+        &self.nfa.modes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Tag;
+
+    // whether each prefix of a random a/b string is accepted by a rule whose
+    // dfa has 2^13 states
+    fn run(cache_size: usize) -> LazyDfa {
+        let pattern = "(a|b)*a".to_string() + &"(a|b)".repeat(12);
+        let spec = vec![(pattern, Tag("A".to_string()))];
+        let mut lazy = LazyDfa::new(spec, cache_size).unwrap();
+        let mut seed = 0x2545f491u32;
+        let mut input = Vec::new();
+        lazy.reset(0, false);
+        for _ in 0..20000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let c = if seed & 1 == 0 { 'a' } else { 'b' };
+            input.push(c);
+            assert!(lazy.step(c));
+            let accepted = input.len() >= 13 && input[input.len() - 13] == 'a';
+            assert_eq!(
+                lazy.accept().is_some(),
+                accepted,
+                "after {} chars",
+                input.len()
+            );
+        }
+        lazy
+    }
+
+    #[test]
+    fn test_cache() {
+        let lazy = run(64 << 20);
+        assert_eq!(lazy.clears, 0);
+        assert!(lazy.states.len() > 1000);
+        assert!(lazy.used <= 64 << 20);
+        let lazy = run(64 << 10);
+        assert!(lazy.clears >= MAX_THRASHING_CLEARS);
+        assert!(lazy.is_simulating());
+    }
+
+    #[test]
+    fn test_cache_reuse() {
+        // a cache that holds the few states lexing needs is never cleared
+        let spec = vec![
+            ("[a-z]+".to_string(), Tag("WORD".to_string())),
+            (" ".to_string(), Tag("SPACE".to_string())),
+        ];
+        let mut lazy = LazyDfa::new(spec, 4096).unwrap();
+        for _ in 0..1000 {
+            for word in ["lazy", "dfa"] {
+                lazy.reset(0, false);
+                for c in word.chars() {
+                    assert!(lazy.step(c));
+                    assert_eq!(lazy.accept(), Some(0));
+                }
+                assert!(!lazy.step(' '));
+            }
+        }
+        assert_eq!(lazy.clears, 0);
+        assert!(lazy.used <= 4096);
+    }
+}
@@ -1,111 +1,265 @@
-use crate::common::{input2internal, internal2input, Tag};
-use crate::dfa::Dfa;
+use crate::ast::TrailingContext;
+use crate::common::{LexSpec, ModeAction, Tag};
+use crate::dfa::{BuildError, Dfa, Limits};
+use crate::engine::{DfaEngine, Engine};
+use crate::lazy::{self, LazyDfa};
+use crate::pike::PikeVm;
+use crate::spec::{self, SpecError};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// how the lexer finds the longest match
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    // the minimized dfa, built before lexing
+    #[default]
+    Dfa,
+    // dfa states built from the nfa while lexing, for specs whose dfa is too
+    // large to build, falls back to simulating the nfa when its cache thrashes
+    LazyDfa,
+    // the nfa simulated directly, for specs that change more often than they
+    // lex much input
+    PikeVm,
+}
+
+// how the dfa backend builds its dfa, all give the same minimized dfa
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Construction {
+    // Thompson's nfa, then subset construction
+    #[default]
+    Thompson,
+    // the direct construction from followpos, without an nfa
+    Followpos,
+    // Brzozowski's derivatives, without an nfa
+    Derivatives,
+}
+
+// settings for building lexers, the flags select the `%if FLAG` regions of
+// the specs read through the builder
+#[derive(Debug, Clone, Default)]
+pub struct LexerBuilder {
+    flags: Vec<String>,
+    backend: Backend,
+    construction: Construction,
+    // the memory cap of the lazy dfa in bytes
+    cache_size: Option<usize>,
+    limits: Limits,
+}
+
+impl LexerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn define(mut self, flag: &str) -> Self {
+        if !self.flags.iter().any(|f| f == flag) {
+            self.flags.push(flag.to_string());
+        }
+        self
+    }
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+    pub fn construction(mut self, construction: Construction) -> Self {
+        self.construction = construction;
+        self
+    }
+    pub fn cache_size(mut self, bytes: usize) -> Self {
+        self.cache_size = Some(bytes);
+        self
+    }
+    // the dfa backend fails with `BuildError::TooLarge` past these
+    pub fn max_states(mut self, states: usize) -> Self {
+        self.limits.states = Some(states);
+        self
+    }
+    pub fn max_transitions(mut self, transitions: usize) -> Self {
+        self.limits.transitions = Some(transitions);
+        self
+    }
+    pub fn time_limit(mut self, time: Duration) -> Self {
+        self.limits.time = Some(time);
+        self
+    }
+    pub fn read_spec(&self, path: &str) -> Result<LexSpec, SpecError> {
+        spec::read_with_flags(path, &self.flags)
+    }
+    pub fn parse_spec(&self, content: &str) -> Result<LexSpec, SpecError> {
+        spec::parse_with_flags(content, &self.flags)
+    }
+    pub fn build<I, S>(&self, input: I, spec: S) -> Result<Lexer<I>, BuildError>
+    where
+        I: Iterator<Item = char>,
+        S: Into<LexSpec>,
+    {
+        let engine: Box<dyn Engine> = match self.backend {
+            Backend::Dfa => {
+                let mut dfa = match self.construction {
+                    Construction::Thompson => {
+                        let mut dfa = Dfa::new(spec)?;
+                        dfa.construct_within(&self.limits)?;
+                        dfa
+                    }
+                    Construction::Followpos => Dfa::from_followpos(spec, &self.limits)?,
+                    Construction::Derivatives => Dfa::from_derivatives(spec, &self.limits)?,
+                };
+                dfa.minimize();
+                Box::new(DfaEngine::new(dfa))
+            }
+            Backend::LazyDfa => Box::new(LazyDfa::new(
+                spec,
+                self.cache_size.unwrap_or(lazy::DEFAULT_CACHE_SIZE),
+            )?),
+            Backend::PikeVm => Box::new(PikeVm::new(spec)?),
+        };
+        Ok(Lexer {
+            input,
+            engine,
+            buffer: VecDeque::new(),
+            pos: 0,
+            at_bol: true,
+            is_done: false,
+            mode: 0,
+            mode_stack: Vec::new(),
+        })
+    }
+}
+
 pub struct Lexer<I>
 where
     I: Iterator<Item = char>,
 {
     pub input: I,
-    dfa: Dfa,
-    last_char: Option<char>,
+    engine: Box<dyn Engine>,
+    // chars read past the end of the last token, they are scanned again
+    buffer: VecDeque<char>,
+    // number of chars consumed by the returned tokens
     pub pos: usize,
+    // whether the previous char was a newline, `^` rules only match then
+    at_bol: bool,
     is_done: bool,
+    mode: usize,
+    mode_stack: Vec<usize>,
 }
 
 impl<I> Lexer<I>
 where
     I: Iterator<Item = char>,
 {
-    pub fn new(input: I, pattern: Vec<(String, Tag)>) -> Self {
-        let mut dfa = Dfa::new(pattern);
-        dfa.construct();
-        dfa.minimize();
-        Lexer {
-            input,
-            dfa,
-            last_char: None,
-            pos: 0,
-            is_done: false,
-        }
+    pub fn new<S: Into<LexSpec>>(input: I, spec: S) -> Self {
+        LexerBuilder::new()
+            .build(input, spec)
+            .unwrap_or_else(|e| panic!("{}", e))
     }
     fn get_next_char(&mut self) -> Option<char> {
-        if let Some(c) = self.last_char {
-            self.last_char = None;
-            let c = input2internal(c);
+        if let Some(c) = self.buffer.pop_front() {
             return Some(c);
         }
-        self.pos += 1;
-        self.input.next().map(|c| input2internal(c))
+        self.input.next()
     }
     pub fn get_next_token(&mut self) -> Option<(String, Tag)> {
-        let mut state = 0;
-        let mut token = String::new();
+        self.engine.reset(self.mode, self.at_bol);
+        let mut read = Vec::new();
         let mut last_accept = None;
+        let mut at_end = false;
         loop {
-            if let Some(c) = self.get_next_char() {
-                if let Some(next_state) = self.dfa.get_next_state(state, c) {
-                    token.push(c);
-                    if let Some(accept_tag) = self.dfa.states[next_state].accept.clone() {
-                        last_accept = Some((token.clone(), accept_tag));
+            let next_char = self.get_next_char();
+            if !read.is_empty() && matches!(next_char, None | Some('\n')) {
+                if let Some(rule) = self.engine.accept_eol() {
+                    last_accept = Some((read.len(), rule));
+                }
+            }
+            if let Some(c) = next_char {
+                read.push(c);
+                if self.engine.step(c) {
+                    if let Some(rule) = self.engine.accept() {
+                        last_accept = Some((read.len(), rule));
                     }
-                    state = next_state;
                 } else {
-                    self.last_char = Some(c);
                     break;
                 }
             } else {
-                self.last_char = None;
-                if last_accept.is_some() {
-                    self.is_done = true;
-                }
+                at_end = true;
                 break;
             }
         }
-        // println!("token: {:?}", token);
-        last_accept.map(|(token, tag)| (token.chars().map(|c| internal2input(c)).collect(), tag))
+        // the trailing context of `r/s` is matched but given back
+        let len = last_accept.map_or(0, |(len, rule)| match self.engine.rule(rule).trailing {
+            Some(TrailingContext::Head(head)) => head,
+            Some(TrailingContext::Trail(trail)) => len - trail,
+            None => len,
+        });
+        for &c in read[len..].iter().rev() {
+            self.buffer.push_front(c);
+        }
+        self.is_done = at_end && self.buffer.is_empty();
+        self.pos += len;
+        if len > 0 {
+            self.at_bol = read[len - 1] == '\n';
+        }
+        let (_, rule) = last_accept?;
+        // the modes of actions are checked by `build`, so switching can't fail
+        let switched = match self.engine.rule(rule).mode_action.clone() {
+            Some(ModeAction::Begin(mode)) => self.begin(&mode),
+            Some(ModeAction::Push(mode)) => self.push_mode(&mode),
+            Some(ModeAction::Pop) => {
+                self.pop_mode();
+                true
+            }
+            None => true,
+        };
+        debug_assert!(switched);
+        Some((
+            read[..len].iter().collect(),
+            self.engine.rule(rule).tag.clone(),
+        ))
+    }
+    pub fn mode(&self) -> &str {
+        &self.engine.modes()[self.mode]
+    }
+    fn mode_index(&self, mode: &str) -> Option<usize> {
+        self.engine.modes().iter().position(|m| m == mode)
+    }
+    // returns false and stays in the current mode if `mode` isn't declared
+    pub fn begin(&mut self, mode: &str) -> bool {
+        let Some(index) = self.mode_index(mode) else {
+            return false;
+        };
+        self.mode = index;
+        true
+    }
+    // like `begin`, the stack is left alone if `mode` isn't declared
+    pub fn push_mode(&mut self, mode: &str) -> bool {
+        let current = self.mode;
+        if !self.begin(mode) {
+            return false;
+        }
+        self.mode_stack.push(current);
+        true
+    }
+    // popping the last mode goes back to INITIAL
+    pub fn pop_mode(&mut self) {
+        self.mode = self.mode_stack.pop().unwrap_or(0);
     }
     pub fn is_done(&self) -> bool {
         self.is_done
     }
 }
-pub fn read_from_lex_file(path: &str) -> Vec<(String, Tag)> {
-    let mut pattern = Vec::new();
-    let content = std::fs::read_to_string(path).unwrap();
-    let mut lines = content.lines();
-    while let Some(line) = lines.next() {
-        if line.is_empty() {
-            continue;
-        }
-        let mut iter = line.split("->");
-        let tag_str = iter.next().unwrap();
-        let pattern_str = iter.next().unwrap();
-        let pattern_str = pattern_str.replace("\\t", "\t");
-        let pattern_str = pattern_str.replace("\\n", "\n");
-        let pattern_str = pattern_str.replace("\\r", "\r");
-        let pattern_str = pattern_str.replace("\\.", "\u{1000}");
-        let pattern_str = pattern_str.replace("\\*", "\u{1001}");
-        let pattern_str = pattern_str.replace("\\+", "\u{1002}");
-        let pattern_str = pattern_str.replace("\\?", "\u{1003}");
-        let pattern_str = pattern_str.replace("\\(", "\u{1004}");
-        let pattern_str = pattern_str.replace("\\)", "\u{1005}");
-        let pattern_str = pattern_str.replace("\\[", "\u{1006}");
-        let pattern_str = pattern_str.replace("\\]", "\u{1007}");
-        let pattern_str = pattern_str.replace("\\|", "\u{1008}");
-        let pattern_str = pattern_str.replace("\\-", "\u{1009}");
-        let pattern_str = pattern_str.replace("\\\\", "\u{100a}");
-        pattern.push((pattern_str.to_string(), Tag(tag_str.to_string())));
-    }
-    pattern
-}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::common::Rule;
+    use crate::spec;
     #[test]
     fn test_lexer() {
-        let pattern = vec![("\"[a-c]*\"".to_string(), Tag("DIGIT".to_string()))];
+        let pattern = vec![("\\\"[a-c]*\\\"".to_string(), Tag("DIGIT".to_string()))];
         let input = "\"1.6f\"".chars();
-        let mut l = Lexer::new(input, pattern);
-        let graph = l.dfa.to_graphviz();
+        let mut dfa = Dfa::new(pattern.clone()).unwrap();
+        dfa.construct().unwrap();
+        dfa.minimize();
+        let graph = dfa.to_graphviz();
         println!("{}", graph);
+        let mut l = Lexer::new(input, pattern);
         let mut tokens = Vec::new();
         while let Some(token) = l.get_next_token() {
             tokens.push(token);
@@ -115,20 +269,605 @@ mod tests {
 
     #[test]
     fn test_read_from_lex_file() {
-        let pattern = read_from_lex_file("/home/zys/repo/seu_lex/demo.lex");
+        let pattern =
+            spec::read_from_lex_file(concat!(env!("CARGO_MANIFEST_DIR"), "/demo.lex")).unwrap();
         println!("{:?}", pattern);
     }
 
     #[test]
     fn test_lexer_from_file() {
-        let pattern = read_from_lex_file("/home/zys/repo/seu_lex/c99_modified.l");
-        let input = "printf(\"result: %lld\", result);".chars();
+        let pattern =
+            spec::read_from_lex_file(concat!(env!("CARGO_MANIFEST_DIR"), "/c99_modified.l"))
+                .unwrap();
+        let input =
+            "#include <stdio.h>\nprintf(\"result: %lld\", result); /* done\n */ x &= ~p->y + 0x1fUL * 1.5e+3f;"
+                .chars();
         let mut l = Lexer::new(input, pattern);
         let mut tokens = Vec::new();
         while let Some(token) = l.get_next_token() {
             tokens.push(token);
         }
         println!("{:?}", tokens);
+        assert!(tokens.contains(&("->".to_string(), Tag("PTR_OP".to_string()))));
+        for constant in ["0x1fUL", "1.5e+3f"] {
+            assert!(tokens.contains(&(constant.to_string(), Tag("CONSTANT".to_string()))));
+        }
+        assert!(l.is_done());
+    }
+
+    #[test]
+    fn test_lexer_from_dialect() {
+        let spec =
+            spec::read_from_lex_file(concat!(env!("CARGO_MANIFEST_DIR"), "/gnu99.l")).unwrap();
+        let input = "static __inline__ typeof(x) *__restrict p;".chars();
+        let mut l = Lexer::new(input, spec);
+        let mut tokens = Vec::new();
+        while let Some((token, tag)) = l.get_next_token() {
+            if tag.0 != "WHITESPACE" {
+                tokens.push((token, tag.0));
+            }
+        }
+        let expected = [
+            ("static", "STATIC"),
+            ("__inline__", "INLINE"),
+            ("typeof", "TYPEOF"),
+            ("(", "("),
+            ("x", "IDENTIFIER"),
+            (")", ")"),
+            ("*", "*"),
+            ("__restrict", "RESTRICT"),
+            ("p", "IDENTIFIER"),
+            (";", ";"),
+        ];
+        assert_eq!(
+            tokens,
+            expected.map(|(token, tag)| (token.to_string(), tag.to_string()))
+        );
+        assert!(l.is_done());
+    }
+
+    #[test]
+    fn test_builder_flags() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/c99_modified.l");
+        let input = "_Atomic int _Static_assert";
+        let tokens = |builder: LexerBuilder| {
+            let spec = builder.read_spec(path).unwrap();
+            let mut l = builder.build(input.chars(), spec).unwrap();
+            let mut tokens = Vec::new();
+            while let Some((_, tag)) = l.get_next_token() {
+                if tag.0 != "WHITESPACE" {
+                    tokens.push(tag.0);
+                }
+            }
+            tokens
+        };
+        assert_eq!(
+            tokens(LexerBuilder::new()),
+            ["IDENTIFIER", "INT", "IDENTIFIER"]
+        );
+        assert_eq!(
+            tokens(LexerBuilder::new().define("C11")),
+            ["ATOMIC", "INT", "STATIC_ASSERT"]
+        );
+        let gnu99 = concat!(env!("CARGO_MANIFEST_DIR"), "/gnu99.l");
+        let spec = LexerBuilder::new().define("C11").read_spec(gnu99).unwrap();
+        assert!(spec.rules.iter().any(|rule| rule.tag.0 == "GENERIC"));
+    }
+
+    #[test]
+    fn test_backends() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/c99_modified.l");
+        let input = include_str!("../src.txt");
+        let tokens = |builder: LexerBuilder| {
+            let spec = builder.read_spec(path).unwrap();
+            let mut l = builder.build(input.chars(), spec).unwrap();
+            let mut tokens = Vec::new();
+            while let Some(token) = l.get_next_token() {
+                tokens.push(token);
+            }
+            assert!(l.is_done());
+            tokens
+        };
+        // the nfa simulation is the reference
+        let expected = tokens(LexerBuilder::new().backend(Backend::PikeVm));
+        assert!(expected.len() > 800);
+        assert_eq!(tokens(LexerBuilder::new()), expected);
+        let followpos = LexerBuilder::new().construction(Construction::Followpos);
+        assert_eq!(tokens(followpos), expected);
+        let derivatives = LexerBuilder::new().construction(Construction::Derivatives);
+        assert_eq!(tokens(derivatives), expected);
+        // a cache of no bytes is cleared for every new state and soon given up
+        for cache_size in [lazy::DEFAULT_CACHE_SIZE, 4096, 0] {
+            let builder = LexerBuilder::new()
+                .backend(Backend::LazyDfa)
+                .cache_size(cache_size);
+            assert_eq!(tokens(builder), expected, "cache of {} bytes", cache_size);
+        }
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let rule =
+            |pattern: &str| crate::common::Rule::new(pattern.to_string(), Tag("A".to_string()));
+        for backend in [Backend::Dfa, Backend::LazyDfa, Backend::PikeVm] {
+            let builder = LexerBuilder::new().backend(backend);
+            let e = builder.build("a".chars(), vec![rule("(")]).err().unwrap();
+            assert!(matches!(e, BuildError::Pattern { .. }), "{}", e);
+            let mut string = rule("a");
+            string.start_conditions = vec!["STR".to_string()];
+            let e = builder.build("a".chars(), vec![string]).err().unwrap();
+            assert!(matches!(&e, BuildError::UndeclaredMode { mode, .. } if mode == "STR"));
+            let mut begin = rule("a");
+            begin.mode_action = Some(ModeAction::Begin("STR".to_string()));
+            let e = builder.build("a".chars(), vec![begin]).err().unwrap();
+            assert_eq!(
+                e.to_string(),
+                "rule A uses the undeclared start condition STR"
+            );
+        }
+        let e = LexerBuilder::new()
+            .construction(Construction::Followpos)
+            .build("a".chars(), vec![rule("a|(")])
+            .err()
+            .unwrap();
+        assert!(matches!(e, BuildError::Pattern { .. }), "{}", e);
+    }
+
+    #[test]
+    fn test_priority_ties() {
+        let rule = |pattern: &str, tag: &str| {
+            let mut rule = crate::common::Rule::new(pattern.to_string(), Tag(tag.to_string()));
+            rule.priority = Some(1);
+            rule
+        };
+        let spec = vec![rule("if", "IF"), rule("i[a-z]", "I2")];
+        for backend in [Backend::Dfa, Backend::LazyDfa, Backend::PikeVm] {
+            let builder = LexerBuilder::new().backend(backend);
+            let e = builder.build("if".chars(), spec.clone()).err().unwrap();
+            assert!(
+                matches!(&e, BuildError::Ambiguous { example, .. } if example == "if"),
+                "{}",
+                e
+            );
+        }
+        // ties that a higher `%prio` always beats are fine
+        let low = |pattern: &str, tag: &str| {
+            let mut rule = rule(pattern, tag);
+            rule.priority = Some(-1);
+            rule
+        };
+        let spec = vec![low("ab", "AB"), low("a[a-z]", "A2"), rule("ab", "KEYWORD")];
+        for backend in [Backend::Dfa, Backend::LazyDfa, Backend::PikeVm] {
+            let builder = LexerBuilder::new().backend(backend);
+            assert!(builder.build("ab".chars(), spec.clone()).is_ok());
+        }
+        // the backends without a dfa don't determinize the rules that can't tie
+        let exponential = "(a|b)*a".to_string() + &"(a|b)".repeat(20);
+        let spec = vec![
+            crate::common::Rule::new(exponential, Tag("A".to_string())),
+            low("x", "X"),
+            low("[xy]", "XY"),
+        ];
+        for backend in [Backend::LazyDfa, Backend::PikeVm] {
+            let started = std::time::Instant::now();
+            let builder = LexerBuilder::new().backend(backend);
+            let e = builder.build("x".chars(), spec.clone()).err().unwrap();
+            assert!(
+                matches!(&e, BuildError::Ambiguous { example, .. } if example == "x"),
+                "{}",
+                e
+            );
+            assert!(started.elapsed() < Duration::from_secs(5));
+        }
+    }
+
+    #[test]
+    fn test_builder_limits() {
+        let spec = vec![(
+            "(a|b)*a".to_string() + &"(a|b)".repeat(16),
+            Tag("A".to_string()),
+        )];
+        let builder = LexerBuilder::new().max_states(100);
+        let e = builder.build("ab".chars(), spec.clone()).err().unwrap();
+        assert!(matches!(e, BuildError::TooLarge { .. }), "{}", e);
+        // the lazy dfa is bounded by its cache instead
+        let builder = builder.backend(Backend::LazyDfa);
+        let mut l = builder.build("abbbbbbbbbbbbbbbbbb".chars(), spec).unwrap();
+        assert_eq!(l.get_next_token().unwrap().0, "abbbbbbbbbbbbbbbb");
+    }
+
+    #[test]
+    fn test_complement_limits() {
+        // the dfa of the operand alone blows up, before the rule's own dfa is built
+        let spec = vec![
+            (
+                "~((a|b)*a".to_string() + &"(a|b)".repeat(17) + ")",
+                Tag("A".to_string()),
+            ),
+            ("c".to_string(), Tag("C".to_string())),
+        ];
+        for construction in [
+            Construction::Thompson,
+            Construction::Followpos,
+            Construction::Derivatives,
+        ] {
+            for builder in [
+                LexerBuilder::new().max_states(100),
+                LexerBuilder::new().time_limit(Duration::from_millis(50)),
+            ] {
+                let started = std::time::Instant::now();
+                let builder = builder.construction(construction);
+                let e = builder.build("ab".chars(), spec.clone()).err().unwrap();
+                let BuildError::TooLarge { rules, .. } = &e else {
+                    panic!("{}", e);
+                };
+                assert_eq!(rules, &[(Tag("A".to_string()), None)]);
+                assert!(started.elapsed() < Duration::from_secs(5));
+            }
+        }
+    }
+
+    #[test]
+    fn test_quoted_pattern() {
+        let pattern = vec![
+            ("\"...\"".to_string(), Tag("ELLIPSIS".to_string())),
+            ("\"||\"".to_string(), Tag("OR_OP".to_string())),
+            ("\"a\\\"b\"".to_string(), Tag("QUOTE".to_string())),
+            ("\"(\"(a|b)+\")\"".to_string(), Tag("GROUP".to_string())),
+        ];
+        let input = "...||a\"b(abba)".chars();
+        let mut l = Lexer::new(input, pattern);
+        let mut tokens = Vec::new();
+        while let Some((token, tag)) = l.get_next_token() {
+            tokens.push((token, tag.0));
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                ("...".to_string(), "ELLIPSIS".to_string()),
+                ("||".to_string(), "OR_OP".to_string()),
+                ("a\"b".to_string(), "QUOTE".to_string()),
+                ("(abba)".to_string(), "GROUP".to_string()),
+            ]
+        );
+        assert!(l.is_done());
+    }
+
+    #[test]
+    fn test_escape_classes() {
+        let pattern = vec![
+            ("\\d+".to_string(), Tag("NUM".to_string())),
+            ("\\w+".to_string(), Tag("WORD".to_string())),
+            ("\\s+".to_string(), Tag("WS".to_string())),
+            ("\\x00|\\u{1F600}".to_string(), Tag("SPECIAL".to_string())),
+            ("\\W".to_string(), Tag("NOT_WORD".to_string())),
+        ];
+        let input = "ab_1 42\x0b\x0c\0\u{1F600}正".chars();
+        let mut l = Lexer::new(input, pattern);
+        let mut tokens = Vec::new();
+        while let Some((token, tag)) = l.get_next_token() {
+            tokens.push((token, tag.0));
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                ("ab_1".to_string(), "WORD".to_string()),
+                (" ".to_string(), "WS".to_string()),
+                ("42".to_string(), "NUM".to_string()),
+                ("\x0b\x0c".to_string(), "WS".to_string()),
+                ("\0".to_string(), "SPECIAL".to_string()),
+                ("\u{1F600}".to_string(), "SPECIAL".to_string()),
+                ("正".to_string(), "NOT_WORD".to_string()),
+            ]
+        );
+        assert!(l.is_done());
+    }
+
+    #[test]
+    fn test_unicode_classes() {
+        let pattern = vec![
+            ("\\p{Numeric}+".to_string(), Tag("NUM".to_string())),
+            (
+                "\\p{IdStart}\\p{IdContinue}*".to_string(),
+                Tag("ID".to_string()),
+            ),
+            ("\\p{Whitespace}+".to_string(), Tag("WS".to_string())),
+            ("\\P{Alphanumeric}".to_string(), Tag("OTHER".to_string())),
+        ];
+        let input = "变量_1 = 42;\u{3000}émile".chars();
+        let mut l = Lexer::new(input, pattern);
+        let mut tokens = Vec::new();
+        while let Some((token, tag)) = l.get_next_token() {
+            tokens.push((token, tag.0));
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                ("变量_1".to_string(), "ID".to_string()),
+                (" ".to_string(), "WS".to_string()),
+                ("=".to_string(), "OTHER".to_string()),
+                (" ".to_string(), "WS".to_string()),
+                ("42".to_string(), "NUM".to_string()),
+                (";".to_string(), "OTHER".to_string()),
+                ("\u{3000}".to_string(), "WS".to_string()),
+                ("émile".to_string(), "ID".to_string()),
+            ]
+        );
+        assert!(l.is_done());
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let mut select = Rule::new("select".to_string(), Tag("SELECT".to_string()));
+        select.case_insensitive = true;
+        let rules = vec![
+            select,
+            Rule::new("[0-9]+(?i:ul?)".to_string(), Tag("INT".to_string())),
+            Rule::new("[a-z]+".to_string(), Tag("ID".to_string())),
+            Rule::new(" ".to_string(), Tag("WS".to_string())),
+        ];
+        let input = "SeLeCt select 12U 3uL SELECT".chars();
+        let mut l = Lexer::new(input, rules);
+        let mut tokens = Vec::new();
+        while let Some((token, tag)) = l.get_next_token() {
+            if tag.0 != "WS" {
+                tokens.push((token, tag.0));
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                ("SeLeCt".to_string(), "SELECT".to_string()),
+                ("select".to_string(), "SELECT".to_string()),
+                ("12U".to_string(), "INT".to_string()),
+                ("3uL".to_string(), "INT".to_string()),
+                ("SELECT".to_string(), "SELECT".to_string()),
+            ]
+        );
+        assert!(l.is_done());
+    }
+
+    #[test]
+    fn test_complement_intersect() {
+        let pattern = vec![
+            (
+                "\"/*\"~(\\p{Any}*\"*/\"\\p{Any}*)\"*/\"".to_string(),
+                Tag("COMMENT".to_string()),
+            ),
+            ("[a-z]+&~(if|else)".to_string(), Tag("ID".to_string())),
+            ("[a-z]+".to_string(), Tag("KEYWORD".to_string())),
+            ("\\s+".to_string(), Tag("WS".to_string())),
+            ("\\/".to_string(), Tag("/".to_string())),
+        ];
+        let input = "if x /* a\n * b */ / else /**/ y */".chars();
+        let mut l = Lexer::new(input, pattern);
+        let mut tokens = Vec::new();
+        while let Some((token, tag)) = l.get_next_token() {
+            if tag.0 != "WS" {
+                tokens.push((token, tag.0));
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                ("if".to_string(), "KEYWORD".to_string()),
+                ("x".to_string(), "ID".to_string()),
+                ("/* a\n * b */".to_string(), "COMMENT".to_string()),
+                ("/".to_string(), "/".to_string()),
+                ("else".to_string(), "KEYWORD".to_string()),
+                ("/**/".to_string(), "COMMENT".to_string()),
+                ("y".to_string(), "ID".to_string()),
+            ]
+        );
+        assert!(!l.is_done());
+    }
+
+    #[test]
+    fn test_rescan_after_failed_match() {
+        let pattern = vec![
+            ("abcd".to_string(), Tag("LONG".to_string())),
+            ("a".to_string(), Tag("A".to_string())),
+            ("bc".to_string(), Tag("BC".to_string())),
+        ];
+        let input = "abcabcd".chars();
+        let mut l = Lexer::new(input, pattern);
+        let mut tokens = Vec::new();
+        while let Some((token, tag)) = l.get_next_token() {
+            tokens.push((token, tag.0));
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                ("a".to_string(), "A".to_string()),
+                ("bc".to_string(), "BC".to_string()),
+                ("abcd".to_string(), "LONG".to_string()),
+            ]
+        );
+        assert!(l.is_done());
+        assert_eq!(l.pos, 7);
+    }
+
+    #[test]
+    fn test_anchors() {
+        let pattern = vec![
+            ("^#[a-z]+".to_string(), Tag("DIRECTIVE".to_string())),
+            ("#".to_string(), Tag("HASH".to_string())),
+            ("[a-z]+$".to_string(), Tag("LAST".to_string())),
+            ("[a-z]+".to_string(), Tag("WORD".to_string())),
+            ("\\s".to_string(), Tag("WS".to_string())),
+        ];
+        let input = "#if a#b c\n#x y".chars();
+        let mut l = Lexer::new(input, pattern);
+        let mut tokens = Vec::new();
+        while let Some((token, tag)) = l.get_next_token() {
+            if tag.0 != "WS" {
+                tokens.push((token, tag.0));
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                ("#if".to_string(), "DIRECTIVE".to_string()),
+                ("a".to_string(), "WORD".to_string()),
+                ("#".to_string(), "HASH".to_string()),
+                ("b".to_string(), "WORD".to_string()),
+                ("c".to_string(), "LAST".to_string()),
+                ("#x".to_string(), "DIRECTIVE".to_string()),
+                ("y".to_string(), "LAST".to_string()),
+            ]
+        );
+        assert!(l.is_done());
+    }
+
+    #[test]
+    fn test_trailing_context() {
+        let pattern = vec![
+            ("DO/[A-Z0-9]*=[A-Z0-9]*,".to_string(), Tag("DO".to_string())),
+            ("[0-9]+/\"..\"".to_string(), Tag("RANGE_START".to_string())),
+            ("[0-9]+\\.[0-9]*".to_string(), Tag("FLOAT".to_string())),
+            ("[0-9]+".to_string(), Tag("INT".to_string())),
+            ("\"..\"".to_string(), Tag("RANGE".to_string())),
+            ("[A-Z][A-Z0-9]*".to_string(), Tag("ID".to_string())),
+            ("[=,.]".to_string(), Tag("PUNCT".to_string())),
+        ];
+        let input = "1..2 DO10I=1,2 DO10I=1.2".chars().filter(|c| *c != ' ');
+        let mut l = Lexer::new(input, pattern);
+        let mut tokens = Vec::new();
+        while let Some((token, tag)) = l.get_next_token() {
+            tokens.push((token, tag.0));
+        }
+        let expected = [
+            ("1", "RANGE_START"),
+            ("..", "RANGE"),
+            ("2", "INT"),
+            ("DO", "DO"),
+            ("10", "INT"),
+            ("I", "ID"),
+            ("=", "PUNCT"),
+            ("1", "INT"),
+            (",", "PUNCT"),
+            ("2", "INT"),
+            ("DO10I", "ID"),
+            ("=", "PUNCT"),
+            ("1.2", "FLOAT"),
+        ];
+        assert_eq!(
+            tokens,
+            expected
+                .iter()
+                .map(|(token, tag)| (token.to_string(), tag.to_string()))
+                .collect::<Vec<_>>()
+        );
+        assert!(l.is_done());
+        // `a*/b` would match nothing before the `b` of "b" forever
+        let pattern = vec![("a*/b".to_string(), Tag("A".to_string()))];
+        let e = LexerBuilder::new()
+            .build("b".chars(), pattern)
+            .err()
+            .unwrap();
+        assert!(matches!(e, BuildError::Pattern { .. }), "{}", e);
+    }
+
+    #[test]
+    fn test_start_conditions() {
+        let spec = spec::parse(
+            r#"%x STRING
+%s PP
+PP_START %begin(PP)->^#
+<PP>PP_END %begin(INITIAL)->\n
+<PP>PP_WORD->[a-z]+
+STRING_START %push(STRING)->\"
+<STRING>STRING_END %pop->\"
+<STRING>INTERP_START %push(INITIAL)->"${"
+<STRING>TEXT->[a-z ]+
+RBRACE %pop->}
+ID->[a-z]+
+<*>WS->[ \n]
+"#,
+        )
+        .unwrap();
+        let input = "#define x\nx \"a ${ \"b\" } c\" y".chars();
+        let mut l = Lexer::new(input, spec);
+        let mut tokens = Vec::new();
+        while let Some((token, tag)) = l.get_next_token() {
+            tokens.push((token, tag.0, l.mode().to_string()));
+        }
+        let expected = [
+            ("#", "PP_START", "PP"),
+            ("define", "PP_WORD", "PP"),
+            (" ", "WS", "PP"),
+            ("x", "PP_WORD", "PP"),
+            ("\n", "PP_END", "INITIAL"),
+            ("x", "ID", "INITIAL"),
+            (" ", "WS", "INITIAL"),
+            ("\"", "STRING_START", "STRING"),
+            ("a ", "TEXT", "STRING"),
+            ("${", "INTERP_START", "INITIAL"),
+            (" ", "WS", "INITIAL"),
+            ("\"", "STRING_START", "STRING"),
+            ("b", "TEXT", "STRING"),
+            ("\"", "STRING_END", "INITIAL"),
+            (" ", "WS", "INITIAL"),
+            ("}", "RBRACE", "STRING"),
+            (" c", "TEXT", "STRING"),
+            ("\"", "STRING_END", "INITIAL"),
+            (" ", "WS", "INITIAL"),
+            ("y", "ID", "INITIAL"),
+        ];
+        assert_eq!(
+            tokens,
+            expected
+                .iter()
+                .map(|(token, tag, mode)| (token.to_string(), tag.to_string(), mode.to_string()))
+                .collect::<Vec<_>>()
+        );
+        assert!(l.is_done());
+        // unknown modes are refused and change nothing
+        assert!(!l.begin("NOPE") && !l.push_mode("NOPE"));
+        assert_eq!(l.mode(), "INITIAL");
+        l.pop_mode();
+        assert_eq!(l.mode(), "INITIAL");
+        assert!(l.push_mode("STRING") && l.begin("PP"));
+        assert_eq!(l.mode(), "PP");
+        l.pop_mode();
+        assert_eq!(l.mode(), "INITIAL");
+    }
+
+    #[test]
+    fn test_flex_spec() {
+        let spec = spec::parse(
+            r#"%x COMMENT
+%%
+"/*"			{ BEGIN(COMMENT); }
+<COMMENT>"*/"		{ BEGIN(INITIAL); }
+<COMMENT>[^*]+|"*"	;
+[a-z]+			{ count(); return(IDENTIFIER); }
+"->"			{ count(); return(PTR_OP); }
+"+"			{ count(); return('+'); }
+[ \t\n]			{ count(); }
+%%
+"#,
+        )
+        .unwrap();
+        let input = "a->b /* x * y */ + c".chars();
+        let mut l = Lexer::new(input, spec);
+        let mut tokens = Vec::new();
+        while let Some((token, tag)) = l.get_next_token() {
+            if !tag.0.is_empty() {
+                tokens.push((token, tag.0));
+            }
+        }
+        assert_eq!(
+            tokens,
+            [
+                ("a", "IDENTIFIER"),
+                ("->", "PTR_OP"),
+                ("b", "IDENTIFIER"),
+                ("+", "+"),
+                ("c", "IDENTIFIER"),
+            ]
+            .map(|(token, tag)| (token.to_string(), tag.to_string()))
+        );
         assert!(l.is_done());
     }
 }
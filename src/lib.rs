@@ -1,8 +1,22 @@
+mod alphabet;
 mod ast;
+mod class;
 mod common;
+mod derivative;
 mod dfa;
+mod engine;
+mod followpos;
+mod lazy;
 mod lexer;
 mod nfa;
+mod pike;
+mod spec;
+mod tables;
+
+pub use common::{LexSpec, Rule, Tag};
+pub use dfa::{BuildError, Limit};
+pub use lexer::{Backend, Construction, Lexer, LexerBuilder};
+pub use spec::SpecError;
 
 use std::ffi::CStr;
 use std::os::raw::c_char;
@@ -13,54 +27,93 @@ use std::sync::Mutex;
 use annotate_snippets::{Level, Renderer, Snippet};
 
 lazy_static! {
-    static ref TOKENS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+    // value, name and the lexer mode after the token
+    static ref TOKENS: Mutex<Vec<(String, String, String)>> = Mutex::new(Vec::new());
     static ref INDEX: Mutex<usize> = Mutex::new(0);
 }
 
+// prints an error pointing at the char starting at byte `pos` of `source`
+fn report(source: &str, origin: &str, title: &str, pos: usize, label: &str) {
+    let len = source[pos..].chars().next().map_or(0, char::len_utf8);
+    let message = Level::Error.title(title).snippet(
+        Snippet::source(source)
+            .line_start(1)
+            .origin(origin)
+            .fold(true)
+            .annotation(Level::Error.span(pos..pos + len).label(label)),
+    );
+    let renderer = Renderer::styled();
+    println!("{}", renderer.render(message));
+}
+
+/// # Safety
+///
+/// `lex_path` and `src_path` must be valid nul-terminated C strings, `flags`
+/// must be one as well or NULL.
 #[no_mangle]
-pub extern "C" fn lexer_init(lex_path: *const c_char, src_path: *const c_char) {
+pub unsafe extern "C" fn lexer_init(
+    lex_path: *const c_char,
+    src_path: *const c_char,
+    flags: *const c_char,
+) {
     let lex_path = unsafe { CStr::from_ptr(lex_path) };
     let src_path = unsafe { CStr::from_ptr(src_path) };
     let src_content = std::fs::read_to_string(src_path.to_str().unwrap()).unwrap();
-    let tags = lexer::read_from_lex_file(lex_path.to_str().unwrap());
-    let mut lexer = lexer::Lexer::new(src_content.chars(), tags);
+    // the `%if` flags are separated by commas or whitespace
+    let mut builder = lexer::LexerBuilder::new();
+    if !flags.is_null() {
+        let flags = unsafe { CStr::from_ptr(flags) }.to_str().unwrap();
+        for flag in flags.split([',', ' ']).filter(|f| !f.is_empty()) {
+            builder = builder.define(flag);
+        }
+    }
+    let spec = match builder.read_spec(lex_path.to_str().unwrap()) {
+        Ok(spec) => spec,
+        Err(e) => {
+            let source = e
+                .path
+                .as_ref()
+                .and_then(|path| std::fs::read_to_string(path).ok());
+            match source {
+                Some(source) if e.line > 0 => {
+                    let line_start = source
+                        .split_inclusive('\n')
+                        .take(e.line - 1)
+                        .map(str::len)
+                        .sum::<usize>();
+                    let pos = (line_start + e.column).min(source.len());
+                    let origin = e.path.as_deref().unwrap_or_default();
+                    report(&source, origin, "invalid lex file", pos, &e.message);
+                }
+                _ => println!("{}", e),
+            }
+            panic!("Lex file error");
+        }
+    };
+    let mut lexer = match builder.build(src_content.chars(), spec) {
+        Ok(lexer) => lexer,
+        Err(e) => {
+            println!("{}", e);
+            panic!("Lex file error");
+        }
+    };
     while let Some((token, tag)) = lexer.get_next_token() {
-        TOKENS.lock().unwrap().push((token, tag.0));
+        let mode = lexer.mode().to_string();
+        TOKENS.lock().unwrap().push((token, tag.0, mode));
     }
     if !lexer.is_done() {
-        let content = src_content.chars().collect::<String>();
-        let lines = content.lines().collect::<Vec<_>>();
-
-        let error_pos = lexer.pos;
-
-        let mut pos = 0;
-        let mut line_no = 0;
-        let mut col_no = 0;
-        let error_token = src_content.chars().nth(error_pos - 1).unwrap_or(' ');
+        let (error_pos, error_token) = src_content
+            .char_indices()
+            .nth(lexer.pos)
+            .unwrap_or((src_content.len(), ' '));
         let error_message = format!("unexpected token for '{}'", error_token);
-        for (i, line) in lines.iter().enumerate() {
-            if pos + line.len() + 1 > error_pos {
-                line_no = i;
-                col_no = error_pos - pos;
-                break;
-            }
-            pos += line.len() + 1;
-        }
-
-        let message = Level::Error.title("unrecongnized token").snippet(
-            Snippet::source(src_content.as_str())
-                .line_start(1)
-                .origin(src_path.to_str().unwrap())
-                .fold(true)
-                .annotation(
-                    Level::Error
-                        .span(error_pos - 1..error_pos - 1)
-                        .label(error_message.as_str()),
-                ),
+        report(
+            &src_content,
+            src_path.to_str().unwrap(),
+            "unrecongnized token",
+            error_pos,
+            &error_message,
         );
-
-        let renderer = Renderer::styled();
-        println!("{}", renderer.render(message));
         panic!("Lexer error");
     }
 }
@@ -98,6 +151,27 @@ pub extern "C" fn lexer_get_token_value(index: usize) -> *const c_char {
     c_str.into_raw()
 }
 
+#[no_mangle]
+pub extern "C" fn lexer_get_token_mode(index: usize) -> *const c_char {
+    let tokens = TOKENS.lock().unwrap();
+    let token = tokens.get(index).unwrap();
+    let c_str = std::ffi::CString::new(token.2.as_str()).unwrap();
+    c_str.into_raw()
+}
+
+// the mode after the last token returned by yylex
+#[no_mangle]
+pub extern "C" fn lexer_get_mode() -> *const c_char {
+    let index = INDEX.lock().unwrap();
+    let tokens = TOKENS.lock().unwrap();
+    let mode = match *index {
+        0 => common::INITIAL,
+        index => tokens[index - 1].2.as_str(),
+    };
+    let c_str = std::ffi::CString::new(mode).unwrap();
+    c_str.into_raw()
+}
+
 extern "C" {
     fn get_token_number(token_name: *const c_char) -> i32;
     fn modify_yytext(token: *const c_char);
@@ -119,6 +193,11 @@ pub extern "C" fn yylex() -> i32 {
     let tokens = TOKENS.lock().unwrap();
     while *index < tokens.len() {
         let token = tokens.get(*index).unwrap();
+        // flex rules whose action returns nothing only skip input
+        if token.1.is_empty() {
+            *index += 1;
+            continue;
+        }
 
         let token_number = get_token_number_inter(token.1.as_str());
         // println!("token: {:?}, token_number: {:?}", token, token_number);
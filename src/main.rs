@@ -0,0 +1,84 @@
+use seu_lex::LexerBuilder;
+use std::io::Read;
+use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
+
+const USAGE: &str = "usage: seu_lex [-D FLAG]... [--max-states N] [--max-transitions N] \
+                     [--time-limit SECONDS] LEX_FILE [SOURCE]";
+
+fn value<T: FromStr>(arg: Option<String>) -> T {
+    arg.and_then(|arg| arg.parse().ok()).unwrap_or_else(|| {
+        eprintln!("{}", USAGE);
+        exit(2)
+    })
+}
+
+// prints the tokens of SOURCE, or of stdin, one `TAG value` per line
+fn main() {
+    let mut builder = LexerBuilder::new();
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if let Some(flag) = arg.strip_prefix("-D") {
+            // both `-D FLAG` and `-DFLAG`
+            let flag = match flag {
+                "" => args.next().unwrap_or_else(|| {
+                    eprintln!("{}", USAGE);
+                    exit(2)
+                }),
+                flag => flag.to_string(),
+            };
+            builder = builder.define(&flag);
+        } else if arg == "--max-states" {
+            builder = builder.max_states(value(args.next()));
+        } else if arg == "--max-transitions" {
+            builder = builder.max_transitions(value(args.next()));
+        } else if arg == "--time-limit" {
+            let seconds: f64 = value(args.next());
+            if !(seconds >= 0.0 && seconds.is_finite()) {
+                eprintln!("{}", USAGE);
+                exit(2);
+            }
+            builder = builder.time_limit(Duration::from_secs_f64(seconds));
+        } else {
+            paths.push(arg);
+        }
+    }
+    let (lex_path, source) = match paths.as_slice() {
+        [lex_path] => {
+            let mut source = String::new();
+            if let Err(e) = std::io::stdin().read_to_string(&mut source) {
+                eprintln!("can't read stdin: {}", e);
+                exit(1);
+            }
+            (lex_path, source)
+        }
+        [lex_path, src_path] => match std::fs::read_to_string(src_path) {
+            Ok(source) => (lex_path, source),
+            Err(e) => {
+                eprintln!("can't read {}: {}", src_path, e);
+                exit(1);
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+    let spec = builder.read_spec(lex_path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    });
+    let mut lexer = builder.build(source.chars(), spec).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    });
+    while let Some((token, tag)) = lexer.get_next_token() {
+        println!("{} {:?}", tag.0, token);
+    }
+    if !lexer.is_done() {
+        eprintln!("unrecognized input at char {}", lexer.pos);
+        exit(1);
+    }
+}
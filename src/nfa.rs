@@ -1,39 +1,177 @@
 use crate::ast;
-use crate::common::{input2internal, Tag};
-use petgraph::dot::{Config, Dot};
+use crate::class::CharClass;
+use crate::common::{LexSpec, ModeAction, Rule, Tag, INITIAL};
+use crate::dfa::{BuildError, Dfa, Limits};
+use petgraph::dot::Dot;
 use petgraph::graph::DiGraph;
 use std::collections::HashMap;
+use std::time::Instant;
+
+// a set of nfa states, cleared in time proportional to its size
+pub struct StateSet {
+    bits: Vec<u64>,
+    members: Vec<usize>,
+}
+
+impl StateSet {
+    pub fn new(size: usize) -> StateSet {
+        StateSet {
+            bits: vec![0; size.div_ceil(64)],
+            members: Vec::new(),
+        }
+    }
+    // makes room for the states below `size`
+    pub fn reserve(&mut self, size: usize) {
+        if self.bits.len() < size.div_ceil(64) {
+            self.bits.resize(size.div_ceil(64), 0);
+        }
+    }
+    // returns whether `state` wasn't in the set yet
+    pub fn insert(&mut self, state: usize) -> bool {
+        let (word, bit) = (state / 64, 1 << (state % 64));
+        let added = self.bits[word] & bit == 0;
+        if added {
+            self.bits[word] |= bit;
+            self.members.push(state);
+        }
+        added
+    }
+    // the sorted members, leaving the set empty
+    pub fn take(&mut self) -> Vec<usize> {
+        for &state in &self.members {
+            self.bits[state / 64] = 0;
+        }
+        let mut members = std::mem::take(&mut self.members);
+        members.sort_unstable();
+        members
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Transition {
     Epsilon,
-    Symbol(char),
+    // `lo..=hi`, a single char has `lo == hi`
+    Range(char, char),
 }
 #[derive(Clone, Debug)]
 pub struct NfaState {
     pub transitions: Vec<(Transition, usize)>,
 }
 
-#[derive(Debug)]
+// one per rule, in rule order
+#[derive(Clone, Debug)]
+pub struct Accept {
+    // usize::MAX when the dfa was built without an nfa
+    pub state: usize,
+    pub tag: Tag,
+    // only accepts when followed by a newline or the end of input
+    pub eol: bool,
+    pub trailing: Option<ast::TrailingContext>,
+    pub mode_action: Option<ModeAction>,
+    pub priority: Option<i32>,
+    // where the rule is in the spec file, for error messages
+    pub line: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+struct RuleAst {
+    pattern: ast::Pattern,
+    tag: Tag,
+    // indices into `Nfa::modes` of the modes the rule is active in
+    modes: Vec<usize>,
+    mode_action: Option<ModeAction>,
+    priority: Option<i32>,
+    line: Option<usize>,
+}
+
+#[derive(Debug, Default)]
 pub struct Nfa {
     pub states: Vec<NfaState>,
-    pub start: usize,
-    pub accept: Vec<(usize, Tag)>,
+    // the start state of every mode and the one used at the beginning of a
+    // line, which also reaches the `^` rules
+    pub starts: Vec<(usize, usize)>,
+    pub accept: Vec<Accept>,
+    // mode names, INITIAL first
+    pub modes: Vec<String>,
+    // the first state of every rule, the states of a rule are numbered
+    // consecutively
+    rule_starts: Vec<usize>,
     new_state: usize,
-    asts: Vec<(ast::AstNode, Tag)>,
+    rules: Vec<RuleAst>,
 }
 
 impl Nfa {
-    pub fn new(pattern: Vec<(String, Tag)>) -> Nfa {
-        let mut asts = Vec::new();
-        for (s, t) in pattern {
-            asts.push((ast::Parser::new(s).parse(), t));
+    pub fn new<S: Into<LexSpec>>(spec: S) -> Result<Nfa, BuildError> {
+        let spec = spec.into();
+        let mut modes = vec![INITIAL.to_string()];
+        modes.extend(spec.start_conditions.iter().map(|sc| sc.name.clone()));
+        let mode_index = |name: &str, rule: &Rule| {
+            modes
+                .iter()
+                .position(|mode| mode == name)
+                .ok_or_else(|| BuildError::UndeclaredMode {
+                    rule: (rule.tag.clone(), rule.line),
+                    mode: name.to_string(),
+                })
+        };
+        let definitions = ast::Definitions::new(&spec.definitions);
+        let mut rules = Vec::new();
+        for rule in spec.rules {
+            let mut pattern = ast::Parser::new(rule.pattern.clone())
+                .with_definitions(&definitions)
+                .parse_pattern()
+                .map_err(|error| BuildError::Pattern {
+                    rule: (rule.tag.clone(), rule.line),
+                    pattern: rule.pattern.clone(),
+                    error,
+                })?;
+            if rule.case_insensitive {
+                pattern.ast = ast::AstNode::IgnoreCase(Box::new(pattern.ast));
+            }
+            let rule_modes = match rule.start_conditions.as_slice() {
+                [] => std::iter::once(0)
+                    .chain((1..modes.len()).filter(|&i| !spec.start_conditions[i - 1].exclusive))
+                    .collect(),
+                [all] if all == "*" => (0..modes.len()).collect(),
+                names => names
+                    .iter()
+                    .map(|name| mode_index(name, &rule))
+                    .collect::<Result<_, _>>()?,
+            };
+            if let Some(ModeAction::Begin(name) | ModeAction::Push(name)) = &rule.mode_action {
+                mode_index(name, &rule)?;
+            }
+            rules.push(RuleAst {
+                pattern,
+                tag: rule.tag,
+                modes: rule_modes,
+                mode_action: rule.mode_action,
+                priority: rule.priority,
+                line: rule.line,
+            });
         }
+        Ok(Nfa {
+            modes,
+            rules,
+            ..Nfa::default()
+        })
+    }
+    pub fn from_asts(asts: Vec<(ast::Pattern, Tag)>) -> Nfa {
+        let rules = asts
+            .into_iter()
+            .map(|(pattern, tag)| RuleAst {
+                pattern,
+                tag,
+                modes: vec![0],
+                mode_action: None,
+                priority: None,
+                line: None,
+            })
+            .collect();
         Nfa {
-            states: Vec::new(),
-            start: 0,
-            accept: Vec::new(),
-            new_state: 0,
-            asts,
+            modes: vec![INITIAL.to_string()],
+            rules,
+            ..Nfa::default()
         }
     }
     fn get_state(&mut self, state: usize) -> &mut NfaState {
@@ -47,20 +185,86 @@ impl Nfa {
         }
         &mut self.states[state]
     }
-    fn construct_one(&mut self, ast: ast::AstNode, tag: Tag) -> usize {
-        let (start, accept) = self.construct_node(ast);
-        self.accept.push((accept, tag));
-
-        start
+    // the pattern to build for `rule`, with the trailing context of `r/s`
+    // appended, and its accept entry without the accept state
+    fn prepare(rule: RuleAst) -> (ast::AstNode, Accept) {
+        let pattern = rule.pattern;
+        let (ast, trailing) = match pattern.trailing {
+            Some((trail, context)) => (
+                ast::AstNode::And(Box::new(pattern.ast), Box::new(trail)),
+                Some(context),
+            ),
+            None => (pattern.ast, None),
+        };
+        let accept = Accept {
+            state: usize::MAX,
+            tag: rule.tag,
+            eol: pattern.eol,
+            trailing,
+            mode_action: rule.mode_action,
+            priority: rule.priority,
+            line: rule.line,
+        };
+        (ast, accept)
     }
-    pub fn construct(&mut self) {
-        self.get_state(self.start);
-        self.new_state += 1;
-        for (ast, tag) in &self.asts.clone() {
-            let start = self.construct_one(ast.clone(), tag.clone());
-            self.get_state(self.start)
-                .transitions
-                .push((Transition::Epsilon, start));
+    fn construct_one(
+        &mut self,
+        rule: RuleAst,
+        limits: &Limits,
+        started: Instant,
+    ) -> Result<usize, BuildError> {
+        let (ast, mut accept) = Self::prepare(rule);
+        let (start, accept_state) = self
+            .construct_node(ast, false, limits, started)
+            .map_err(|e| e.blame(&accept))?;
+        accept.state = accept_state;
+        self.accept.push(accept);
+        Ok(start)
+    }
+    // the rules for building a dfa without states in between, as the pattern
+    // to build, whether it only matches at the beginning of a line and the
+    // modes it is active in, `accept` gets entries without accept states
+    pub fn patterns(&mut self) -> Vec<(ast::AstNode, bool, Vec<usize>)> {
+        let mut patterns = Vec::new();
+        for rule in self.rules.clone() {
+            let (bol, modes) = (rule.pattern.bol, rule.modes.clone());
+            let (ast, accept) = Self::prepare(rule);
+            self.accept.push(accept);
+            patterns.push((ast, bol, modes));
+        }
+        patterns
+    }
+    pub fn construct(&mut self) -> Result<(), BuildError> {
+        self.construct_within(&Limits::default(), Instant::now())
+    }
+    // the dfas of `~r` and `r & s` are built within `limits`, counting the
+    // time from `started`
+    pub fn construct_within(
+        &mut self,
+        limits: &Limits,
+        started: Instant,
+    ) -> Result<(), BuildError> {
+        self.starts = (0..self.modes.len())
+            .map(|mode| (2 * mode, 2 * mode + 1))
+            .collect();
+        self.new_state = 2 * self.modes.len();
+        self.get_state(self.new_state - 1);
+        for rule in self.rules.clone() {
+            let bol = rule.pattern.bol;
+            let modes = rule.modes.clone();
+            self.rule_starts.push(self.new_state);
+            let start = self.construct_one(rule, limits, started)?;
+            for mode in modes {
+                let (mode_start, mode_bol_start) = self.starts[mode];
+                if !bol {
+                    self.get_state(mode_start)
+                        .transitions
+                        .push((Transition::Epsilon, start));
+                }
+                self.get_state(mode_bol_start)
+                    .transitions
+                    .push((Transition::Epsilon, start));
+            }
         }
         if self.states.len() <= self.new_state {
             self.states.resize(
@@ -70,12 +274,64 @@ impl Nfa {
                 },
             );
         }
+        Ok(())
     }
-    fn construct_node(&mut self, node: ast::AstNode) -> (usize, usize) {
-        match node {
+    // splices a determinized sub-automaton in, with one accept state for all of its accepting states
+    fn construct_dfa(&mut self, dfa: &Dfa) -> (usize, usize) {
+        // states that can no longer reach an accept are dropped, otherwise the
+        // lexer would keep reading after `~r` can't match anymore
+        let live = dfa.live_states();
+        let offset = self.new_state;
+        self.new_state += dfa.states.len();
+        let accept = self.new_state;
+        self.new_state += 1;
+        for i in 0..dfa.states.len() {
+            for &(lo, hi, next) in dfa.edges(i) {
+                if !live[next] {
+                    continue;
+                }
+                self.get_state(offset + i)
+                    .transitions
+                    .push((Transition::Range(lo, hi), offset + next));
+            }
+            if dfa.states[i].accept.is_some() {
+                self.get_state(offset + i)
+                    .transitions
+                    .push((Transition::Epsilon, accept));
+            }
+        }
+        self.get_state(accept);
+        (offset, accept)
+    }
+    fn construct_node(
+        &mut self,
+        node: ast::AstNode,
+        ignore_case: bool,
+        limits: &Limits,
+        started: Instant,
+    ) -> Result<(usize, usize), BuildError> {
+        let with_case = |node: Box<ast::AstNode>| {
+            if ignore_case {
+                ast::AstNode::IgnoreCase(node)
+            } else {
+                *node
+            }
+        };
+        let states = match node {
+            ast::AstNode::Complement(node) => {
+                let dfa = Dfa::from_ast(with_case(node), limits, started)?.complement();
+                self.construct_dfa(&dfa)
+            }
+            ast::AstNode::Intersect(left, right) => {
+                let left = Dfa::from_ast(with_case(left), limits, started)?;
+                let right = Dfa::from_ast(with_case(right), limits, started)?;
+                self.construct_dfa(&left.intersect(&right))
+            }
             ast::AstNode::And(left, right) => {
-                let (left_start, left_accept) = self.construct_node(*left);
-                let (right_start, right_accept) = self.construct_node(*right);
+                let (left_start, left_accept) =
+                    self.construct_node(*left, ignore_case, limits, started)?;
+                let (right_start, right_accept) =
+                    self.construct_node(*right, ignore_case, limits, started)?;
                 self.get_state(left_accept)
                     .transitions
                     .push((Transition::Epsilon, right_start));
@@ -86,8 +342,10 @@ impl Nfa {
                 self.new_state += 1;
                 let accept = self.new_state;
                 self.new_state += 1;
-                let (left_start, left_accept) = self.construct_node(*left);
-                let (right_start, right_accept) = self.construct_node(*right);
+                let (left_start, left_accept) =
+                    self.construct_node(*left, ignore_case, limits, started)?;
+                let (right_start, right_accept) =
+                    self.construct_node(*right, ignore_case, limits, started)?;
                 self.get_state(start)
                     .transitions
                     .push((Transition::Epsilon, left_start));
@@ -107,7 +365,8 @@ impl Nfa {
                 self.new_state += 1;
                 let accept = self.new_state;
                 self.new_state += 1;
-                let (node_start, node_accept) = self.construct_node(*node);
+                let (node_start, node_accept) =
+                    self.construct_node(*node, ignore_case, limits, started)?;
                 self.get_state(start)
                     .transitions
                     .push((Transition::Epsilon, node_start));
@@ -127,7 +386,8 @@ impl Nfa {
                 self.new_state += 1;
                 let accept = self.new_state;
                 self.new_state += 1;
-                let (node_start, node_accept) = self.construct_node(*node);
+                let (node_start, node_accept) =
+                    self.construct_node(*node, ignore_case, limits, started)?;
                 self.get_state(start)
                     .transitions
                     .push((Transition::Epsilon, node_start));
@@ -144,7 +404,8 @@ impl Nfa {
                 self.new_state += 1;
                 let accept = self.new_state;
                 self.new_state += 1;
-                let (node_start, node_accept) = self.construct_node(*node);
+                let (node_start, node_accept) =
+                    self.construct_node(*node, ignore_case, limits, started)?;
                 self.get_state(start)
                     .transitions
                     .push((Transition::Epsilon, node_start));
@@ -156,6 +417,13 @@ impl Nfa {
                     .push((Transition::Epsilon, accept));
                 (start, accept)
             }
+            ast::AstNode::IgnoreCase(node) => self.construct_node(*node, true, limits, started)?,
+            ast::AstNode::Char(c) if ignore_case => self.construct_node(
+                ast::AstNode::Class(CharClass::from_range(c, c)),
+                true,
+                limits,
+                started,
+            )?,
             ast::AstNode::Char(c) => {
                 let start = self.new_state;
                 self.new_state += 1;
@@ -163,53 +431,110 @@ impl Nfa {
                 self.new_state += 1;
                 self.get_state(start)
                     .transitions
-                    .push((Transition::Symbol(c), accept));
+                    .push((Transition::Range(c, c), accept));
                 (start, accept)
             }
-            ast::AstNode::Span(start_char, end_char) => {
+            ast::AstNode::Class(class) => {
+                let class = if ignore_case {
+                    class.case_fold()
+                } else {
+                    class
+                };
                 let start = self.new_state;
                 self.new_state += 1;
                 let accept = self.new_state;
                 self.new_state += 1;
-                for c in start_char..=end_char {
-                    let c = input2internal(c as char);
+                for &(lo, hi) in class.ranges() {
                     self.get_state(start)
                         .transitions
-                        .push((Transition::Symbol(c as char), accept));
+                        .push((Transition::Range(lo, hi), accept));
                 }
                 (start, accept)
             }
+        };
+        Ok(states)
+    }
+
+    // adds `state` and everything reachable from it by epsilon moves to `set`
+    pub fn add_epsilon_closure(&self, state: usize, set: &mut StateSet) {
+        let mut stack = vec![state];
+        set.insert(state);
+        while let Some(s) = stack.pop() {
+            for (t, next) in &self.states[s].transitions {
+                if let Transition::Epsilon = t {
+                    if set.insert(*next) {
+                        stack.push(*next);
+                    }
+                }
+            }
+        }
+    }
+    // the closure of every state a char leads to, the only closures subset
+    // construction needs besides the start states
+    pub fn target_closures(&self) -> HashMap<usize, Vec<usize>> {
+        let mut closures = HashMap::new();
+        let mut set = StateSet::new(self.states.len());
+        for state in &self.states {
+            for (t, next) in &state.transitions {
+                if let Transition::Range(..) = t {
+                    closures.entry(*next).or_insert_with(|| {
+                        self.add_epsilon_closure(*next, &mut set);
+                        set.take()
+                    });
+                }
+            }
+        }
+        closures
+    }
+    // the rule a state was built for, none for the start states
+    pub fn rule_owning(&self, state: usize) -> Option<usize> {
+        self.rule_starts
+            .partition_point(|&first| first <= state)
+            .checked_sub(1)
+    }
+    // the rule accepted in each nfa state
+    pub fn rule_of(&self) -> HashMap<usize, usize> {
+        let mut rule_of = HashMap::new();
+        for (rule, accept) in self.accept.iter().enumerate() {
+            rule_of.insert(accept.state, rule);
         }
+        rule_of
     }
 
+    #[allow(dead_code)]
     fn to_graphviz(&self) -> String {
         let mut graph = DiGraph::<String, String>::new();
         let mut state_map = HashMap::new();
-        for (i, state) in self.states.iter().enumerate() {
+        for i in 0..self.states.len() {
             let mut label = format!("State {}", i);
-            if i == self.start {
-                label += " (start)";
+            for (mode, &(start, bol_start)) in self.starts.iter().enumerate() {
+                if i == start {
+                    label += &format!(" (start: {})", self.modes[mode]);
+                }
+                if i == bol_start {
+                    label += &format!(" (bol start: {})", self.modes[mode]);
+                }
             }
-            for (accept, tag) in &self.accept {
-                if i == *accept {
-                    label += &format!(" (accept: {})", tag.0);
+            for accept in &self.accept {
+                if i == accept.state {
+                    label += &format!(" (accept: {})", accept.tag.0);
                 }
             }
             let node_index = graph.add_node(label);
             state_map.insert(i, node_index);
         }
-        println!("{:?}", state_map);
         for (i, state) in self.states.iter().enumerate() {
             for (transition, next) in &state.transitions {
                 let edge_label = match transition {
                     Transition::Epsilon => "ε".to_string(),
-                    Transition::Symbol(c) => c.to_string(),
+                    Transition::Range(lo, hi) if lo == hi => lo.to_string(),
+                    Transition::Range(lo, hi) => format!("{}-{}", lo, hi),
                 };
                 graph.add_edge(state_map[&i], state_map[next], edge_label);
             }
         }
 
-        let mut dot = Dot::new(&graph);
+        let dot = Dot::new(&graph);
         // dot.set_graph_id("nfa");
         format!("{:?}", dot)
     }
@@ -224,9 +549,29 @@ mod tests {
             ("(a|b)+[a-c]".to_string(), Tag("A正则".to_string())),
             ("(a|b)*abb".to_string(), Tag("B正则".to_string())),
         ];
-        let mut nfa = Nfa::new(pattern);
-        nfa.construct();
+        let mut nfa = Nfa::new(pattern).unwrap();
+        nfa.construct().unwrap();
         let dot = nfa.to_graphviz();
         println!("{}", dot);
     }
+
+    #[test]
+    fn test_class_as_ranges() {
+        let pattern = vec![
+            ("[一-龥]+".to_string(), Tag("HAN".to_string())),
+            (
+                "\\p{IdStart}\\p{IdContinue}*".to_string(),
+                Tag("ID".to_string()),
+            ),
+        ];
+        let mut nfa = Nfa::new(pattern).unwrap();
+        nfa.construct().unwrap();
+        let edges = nfa
+            .states
+            .iter()
+            .flat_map(|state| &state.transitions)
+            .filter(|(t, _)| !matches!(t, Transition::Epsilon))
+            .count();
+        assert!(edges < 2000);
+    }
 }
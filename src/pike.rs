@@ -0,0 +1,144 @@
+use crate::common::LexSpec;
+use crate::dfa::{BuildError, Dfa};
+use crate::engine::Engine;
+use crate::nfa::{self, Nfa, StateSet};
+
+// simulates the nfa with the set of states every thread can be in, nothing
+// is built besides the nfa, so it starts at once but steps slower than a dfa
+pub struct PikeVm {
+    nfa: Nfa,
+    // the rule accepted in every nfa state
+    rule_of: Vec<Option<usize>>,
+    threads: Vec<usize>,
+    set: StateSet,
+    stack: Vec<usize>,
+    accept: Option<usize>,
+    accept_eol: Option<usize>,
+}
+
+impl PikeVm {
+    pub fn new<S: Into<LexSpec>>(spec: S) -> Result<PikeVm, BuildError> {
+        let spec = spec.into();
+        let mut nfa = Nfa::new(spec.clone())?;
+        Dfa::check_priorities(&spec)?;
+        nfa.construct()?;
+        let mut rule_of = vec![None; nfa.states.len()];
+        for (rule, accept) in nfa.accept.iter().enumerate() {
+            rule_of[accept.state] = Some(rule);
+        }
+        Ok(PikeVm {
+            rule_of,
+            threads: Vec::new(),
+            set: StateSet::new(nfa.states.len()),
+            stack: Vec::new(),
+            accept: None,
+            accept_eol: None,
+            nfa,
+        })
+    }
+    // adds a thread for `state` and for every state its epsilon moves reach
+    fn add_thread(&mut self, state: usize) {
+        if !self.set.insert(state) {
+            return;
+        }
+        self.stack.push(state);
+        while let Some(s) = self.stack.pop() {
+            for (t, next) in &self.nfa.states[s].transitions {
+                if let nfa::Transition::Epsilon = t {
+                    if self.set.insert(*next) {
+                        self.stack.push(*next);
+                    }
+                }
+            }
+        }
+    }
+    // moves to the threads added since the last call
+    fn switch_threads(&mut self) {
+        self.threads = self.set.take();
+        let rules = self
+            .threads
+            .iter()
+            .filter_map(|&s| self.rule_of[s])
+            .collect::<Vec<_>>();
+        // the states of a rule come after those of the rules before it
+        self.accept = Dfa::winning_rule(&self.nfa.accept, &rules, false);
+        self.accept_eol = Dfa::winning_rule(&self.nfa.accept, &rules, true);
+    }
+}
+
+impl Engine for PikeVm {
+    fn reset(&mut self, mode: usize, bol: bool) {
+        let (start, bol_start) = self.nfa.starts[mode];
+        self.add_thread(if bol { bol_start } else { start });
+        self.switch_threads();
+    }
+    fn step(&mut self, c: char) -> bool {
+        let threads = std::mem::take(&mut self.threads);
+        for &s in &threads {
+            for i in 0..self.nfa.states[s].transitions.len() {
+                if let (nfa::Transition::Range(lo, hi), next) = self.nfa.states[s].transitions[i] {
+                    if lo <= c && c <= hi {
+                        self.add_thread(next);
+                    }
+                }
+            }
+        }
+        self.switch_threads();
+        !self.threads.is_empty()
+    }
+    fn accept(&self) -> Option<usize> {
+        self.accept
+    }
+    fn accept_eol(&self) -> Option<usize> {
+        self.accept_eol
+    }
+    fn rule(&self, rule: usize) -> &nfa::Accept {
+        &self.nfa.accept[rule]
+    }
+    fn modes(&self) -> &[String] {
+        &self.nfa.modes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dfa::tests::feature_spec;
+    use crate::engine::DfaEngine;
+
+    #[test]
+    fn test_against_dfa() {
+        let spec = feature_spec();
+        let mut dfa = Dfa::new(spec.clone()).unwrap();
+        dfa.construct().unwrap();
+        dfa.minimize();
+        let mut dfa = DfaEngine::new(dfa);
+        let mut vm = PikeVm::new(spec).unwrap();
+        let chars = [
+            'a', 'b', 'c', 'e', 'f', 'i', 'x', 'S', '#', '"', '*', '/', '0', 'p', ' ',
+        ];
+        let mut seed = 0x2545f491u32;
+        for _ in 0..3000 {
+            let mut input = String::new();
+            let mode = (seed % 2) as usize;
+            let bol = seed & 4 == 0;
+            dfa.reset(mode, bol);
+            vm.reset(mode, bol);
+            loop {
+                assert_eq!(vm.accept(), dfa.accept(), "{:?}", input);
+                assert_eq!(vm.accept_eol(), dfa.accept_eol(), "{:?}", input);
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                let c = chars[(seed >> 8) as usize % chars.len()];
+                input.push(c);
+                let stepped = dfa.step(c);
+                assert_eq!(vm.step(c), stepped, "{:?}", input);
+                // `~(a*)` never stops matching
+                if !stepped || input.len() == 20 {
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1199 @@
+use crate::ast;
+use crate::common::{Definition, LexSpec, ModeAction, Rule, StartCondition, Tag, INITIAL};
+use std::collections::HashSet;
+use std::fmt;
+use std::iter::Enumerate;
+use std::path::{Path, PathBuf};
+use std::str::Lines;
+
+type LineIter<'a> = Enumerate<Lines<'a>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecError {
+    pub path: Option<String>,
+    // 1-based, 0 when the error isn't about a single line
+    pub line: usize,
+    // byte offset in the line
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.path, self.line) {
+            (Some(path), 0) => write!(f, "{}: ", path)?,
+            (Some(path), line) => write!(f, "{}:{}: ", path, line)?,
+            (None, 0) => {}
+            (None, line) => write!(f, "line {}: ", line)?,
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for SpecError {}
+
+fn error<T>(line: usize, column: usize, message: impl Into<String>) -> Result<T, SpecError> {
+    Err(SpecError {
+        path: None,
+        line,
+        column,
+        message: message.into(),
+    })
+}
+
+#[allow(dead_code)]
+pub fn read_from_lex_file(path: &str) -> Result<LexSpec, SpecError> {
+    read_with_flags(path, &[])
+}
+
+// `flags` select the `%if FLAG` regions, in included files as well
+pub fn read_with_flags(path: &str, flags: &[String]) -> Result<LexSpec, SpecError> {
+    read_file(Path::new(path), flags, &mut Includes::default())
+}
+
+// the files being read, to report include cycles, and every file read so
+// far, including a file twice would define everything in it twice
+#[derive(Default)]
+struct Includes {
+    stack: Vec<PathBuf>,
+    read: HashSet<PathBuf>,
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn read_file(path: &Path, flags: &[String], includes: &mut Includes) -> Result<LexSpec, SpecError> {
+    let content = std::fs::read_to_string(path)
+        .or_else(|e| error(0, 0, format!("can't read {}: {}", path.display(), e)))?;
+    includes.stack.push(path.to_path_buf());
+    includes.read.insert(canonical(path));
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let spec = parse_in(&content, dir, flags, includes);
+    includes.stack.pop();
+    // errors from included files already name their file
+    spec.map_err(|e| SpecError {
+        path: e.path.or_else(|| Some(path.display().to_string())),
+        ..e
+    })
+}
+
+// reads the flex layout when there is a `%%` line, the `NAME->regex` format otherwise
+#[allow(dead_code)]
+pub fn parse(content: &str) -> Result<LexSpec, SpecError> {
+    parse_with_flags(content, &[])
+}
+
+pub fn parse_with_flags(content: &str, flags: &[String]) -> Result<LexSpec, SpecError> {
+    parse_in(content, Path::new(""), flags, &mut Includes::default())
+}
+
+// `dir` is where `%include` paths are resolved from
+fn parse_in(
+    content: &str,
+    dir: &Path,
+    flags: &[String],
+    includes: &mut Includes,
+) -> Result<LexSpec, SpecError> {
+    let content = &select_lines(content, flags)?;
+    let (spec, columns) = if content.lines().any(|line| line.trim_end() == "%%") {
+        parse_flex(content)?
+    } else {
+        parse_arrows(content, dir, flags, includes)?
+    };
+    check_duplicates(&spec.definitions)?;
+    let definitions = ast::Definitions::new(&spec.definitions);
+    for definition in &spec.definitions {
+        if let Err(message) = definitions.get(&definition.name) {
+            return error(definition.line.unwrap_or(0), 0, message);
+        }
+    }
+    // included rules were checked with their own file
+    for (rule, column) in spec.rules.iter().zip(columns) {
+        if let Some(column) = column {
+            check_pattern(&rule.pattern, &definitions, rule.line.unwrap_or(0), column)?;
+        }
+    }
+    check_modes(&spec)?;
+    Ok(spec)
+}
+
+// `%if FLAG` ... `%else` ... `%endif` keep the lines up to `%else` only when
+// FLAG is set, `%if !FLAG` only when it isn't, the dropped lines and the
+// directives are blanked so the line numbers in errors stay right
+fn select_lines(content: &str, flags: &[String]) -> Result<String, SpecError> {
+    // the line of every open `%if`, whether its lines are kept and whether
+    // its `%else` was seen
+    let mut open: Vec<(usize, bool, bool)> = Vec::new();
+    let mut selected = String::with_capacity(content.len());
+    for (no, line) in content.lines().enumerate() {
+        let no = no + 1;
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("%if") => {
+                let column = line.find("%if").unwrap() + "%if".len();
+                let flag = match (words.next(), words.next()) {
+                    (Some(flag), None) => flag,
+                    _ => return error(no, column, "expected a single flag after %if"),
+                };
+                let (name, negated) = match flag.strip_prefix('!') {
+                    Some(name) => (name, true),
+                    None => (flag, false),
+                };
+                if !ast::is_definition_name(name) {
+                    let column = column + line[column..].find(flag).unwrap();
+                    return error(no, column, format!("invalid flag {}", flag));
+                }
+                let set = flags.iter().any(|f| f == name);
+                open.push((no, set != negated, false));
+            }
+            Some("%else") => match open.last_mut() {
+                Some((_, keep, seen_else @ false)) => {
+                    *keep = !*keep;
+                    *seen_else = true;
+                }
+                Some(_) => return error(no, 0, "second %else for the same %if"),
+                None => return error(no, 0, "%else without %if"),
+            },
+            Some("%endif") => match open.pop() {
+                Some(_) => {}
+                None => return error(no, 0, "%endif without %if"),
+            },
+            _ if open.iter().all(|&(_, keep, _)| keep) => selected.push_str(line),
+            _ => {}
+        }
+        selected.push('\n');
+    }
+    match open.last() {
+        Some(&(no, _, _)) => error(no, 0, "%if without %endif"),
+        None => Ok(selected),
+    }
+}
+
+// a name can be defined once, included definitions have no line in this file
+fn check_duplicates(definitions: &[Definition]) -> Result<(), SpecError> {
+    for (i, definition) in definitions.iter().enumerate() {
+        let Some(first) = definitions[..i].iter().find(|d| d.name == definition.name) else {
+            continue;
+        };
+        let (line, message) = match (first.line, definition.line) {
+            (Some(first), Some(line)) => (line, format!("already defined on line {}", first)),
+            (first, line) => (
+                first.or(line).unwrap_or(0),
+                "also defined in an included file".to_string(),
+            ),
+        };
+        return error(line, 0, format!("{{{}}} is {}", definition.name, message));
+    }
+    Ok(())
+}
+
+// every mode a rule names has to be declared
+fn check_modes(spec: &LexSpec) -> Result<(), SpecError> {
+    let declared =
+        |name: &str| name == INITIAL || spec.start_conditions.iter().any(|sc| sc.name == name);
+    for rule in &spec.rules {
+        let line = rule.line.unwrap_or(0);
+        for name in &rule.start_conditions {
+            if name != "*" && !declared(name) {
+                return error(line, 0, format!("undeclared start condition {}", name));
+            }
+        }
+        if let Some(ModeAction::Begin(name) | ModeAction::Push(name)) = &rule.mode_action {
+            if !declared(name) {
+                return error(line, 0, format!("undeclared start condition {}", name));
+            }
+        }
+    }
+    Ok(())
+}
+
+// `column` is where the pattern starts in its line
+fn check_pattern(
+    pattern: &str,
+    definitions: &ast::Definitions,
+    line: usize,
+    column: usize,
+) -> Result<(), SpecError> {
+    ast::Parser::new(pattern.to_string())
+        .with_definitions(definitions)
+        .parse_pattern()
+        .map(|_| ())
+        .or_else(|e| {
+            let offset = pattern
+                .char_indices()
+                .nth(e.pos)
+                .map_or(pattern.len(), |(i, _)| i);
+            error(line, column + offset, e.message)
+        })
+}
+
+// trailing whitespace is dropped unless it is escaped, so `\ ` or `" "` match a space at the end
+fn trim_pattern(pattern: &str) -> &str {
+    let trimmed = pattern.trim_end();
+    let backslashes = trimmed.chars().rev().take_while(|&c| c == '\\').count();
+    match pattern[trimmed.len()..].chars().next() {
+        Some(c) if backslashes % 2 == 1 => &pattern[..trimmed.len() + c.len_utf8()],
+        _ => trimmed,
+    }
+}
+
+// `%s NAME...` and `%x NAME...` declare start conditions
+fn parse_declaration(line: &str, spec: &mut LexSpec) -> bool {
+    let mut words = line.split_whitespace();
+    let exclusive = match words.next() {
+        Some("%s") => false,
+        Some("%x") => true,
+        _ => return false,
+    };
+    for name in words {
+        spec.start_conditions.push(StartCondition {
+            name: name.to_string(),
+            exclusive,
+        });
+    }
+    true
+}
+
+// `NAME regex` defines `{NAME}`
+fn parse_definition(line: &str, no: usize) -> Option<Definition> {
+    let (name, pattern) = line.split_once(char::is_whitespace)?;
+    let pattern = trim_pattern(pattern.trim_start());
+    if !ast::is_definition_name(name) || pattern.is_empty() {
+        return None;
+    }
+    Some(Definition {
+        name: name.to_string(),
+        pattern: pattern.to_string(),
+        line: Some(no),
+    })
+}
+
+// `%keywords while for _Bool=BOOL` tags every word with its uppercase name,
+// `%literals ; { ->` tags every symbol with itself, each entry is a rule of its own
+fn parse_bulk(line: &str, no: usize) -> Result<Option<Vec<(Rule, usize)>>, SpecError> {
+    let mut entries = line.split_whitespace();
+    let keywords = match entries.next() {
+        Some("%keywords") => true,
+        Some("%literals") => false,
+        _ => return Ok(None),
+    };
+    let mut rules = Vec::new();
+    let mut end = 0;
+    for entry in entries {
+        let column = end + line[end..].find(entry).unwrap();
+        end = column + entry.len();
+        let (text, tag) = match entry.split_once('=') {
+            Some((word, tag)) if keywords => (word, tag.to_string()),
+            _ if keywords => (entry, entry.to_uppercase()),
+            _ => (entry, entry.to_string()),
+        };
+        if keywords && !ast::is_definition_name(text) {
+            return error(no, column, format!("invalid keyword {}", text));
+        }
+        let quoted = text.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut rule = Rule::new(format!("\"{}\"", quoted), Tag(tag));
+        rule.line = Some(no);
+        rules.push((rule, column));
+    }
+    Ok(Some(rules))
+}
+
+// `%include "base.l"`, the path is relative to the including file
+fn parse_include(
+    line: &str,
+    no: usize,
+    dir: &Path,
+    flags: &[String],
+    includes: &mut Includes,
+) -> Result<Option<LexSpec>, SpecError> {
+    let mut words = line.split_whitespace();
+    if words.next() != Some("%include") {
+        return Ok(None);
+    }
+    let column = line.find("%include").unwrap() + "%include".len() + 1;
+    let file = line[column - 1..].trim();
+    let Some(file) = file.strip_prefix('"').and_then(|f| f.strip_suffix('"')) else {
+        return error(no, column, "expected a quoted file name");
+    };
+    let path = dir.join(file);
+    if let Some(i) = includes
+        .stack
+        .iter()
+        .position(|p| canonical(p) == canonical(&path))
+    {
+        let chain = includes.stack[i..]
+            .iter()
+            .chain([&path])
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>();
+        return error(no, column, format!("include cycle: {}", chain.join(" -> ")));
+    }
+    if !path.is_file() {
+        return error(no, column, format!("can't read {}", path.display()));
+    }
+    // a diamond of includes, each file has to be included once
+    if includes.read.contains(&canonical(&path)) {
+        return error(
+            no,
+            column,
+            format!("{} is already included", path.display()),
+        );
+    }
+    read_file(&path, flags, includes).map(Some)
+}
+
+// included rules go where the `%include` is, `columns` marks them with None
+fn merge(spec: &mut LexSpec, columns: &mut Vec<Option<usize>>, included: LexSpec) {
+    for sc in included.start_conditions {
+        if !spec.start_conditions.iter().any(|s| s.name == sc.name) {
+            spec.start_conditions.push(sc);
+        }
+    }
+    spec.definitions.extend(
+        included
+            .definitions
+            .into_iter()
+            .map(|d| Definition { line: None, ..d }),
+    );
+    columns.extend(included.rules.iter().map(|_| None));
+    spec.rules.extend(included.rules);
+    spec.code.extend(included.code);
+}
+
+// `%remove TAG...` drops the included rules with these tags, a rule marked
+// `%override` replaces them and takes the place of the first one
+fn apply_overrides(
+    spec: &mut LexSpec,
+    columns: &mut Vec<Option<usize>>,
+    overrides: Vec<(Rule, usize)>,
+    removals: Vec<(String, usize, usize)>,
+) -> Result<(), SpecError> {
+    let inherited = |rules: &[Rule], columns: &[Option<usize>], tag: &str| {
+        rules
+            .iter()
+            .zip(columns)
+            .any(|(rule, column)| column.is_none() && rule.tag.0 == tag)
+    };
+    for (tag, no, column) in &removals {
+        if !inherited(&spec.rules, columns, tag) {
+            return error(*no, *column, format!("no included rule {} to remove", tag));
+        }
+    }
+    for (rule, _) in &overrides {
+        if !inherited(&spec.rules, columns, &rule.tag.0) {
+            let message = format!("no included rule {} to override", rule.tag.0);
+            return error(rule.line.unwrap_or(0), 0, message);
+        }
+    }
+    let rules = std::mem::take(&mut spec.rules);
+    let old_columns = std::mem::take(columns);
+    let mut replaced = Vec::new();
+    for (rule, column) in rules.into_iter().zip(old_columns) {
+        let tag = &rule.tag.0;
+        if column.is_some() {
+            spec.rules.push(rule);
+            columns.push(column);
+        } else if overrides.iter().any(|(o, _)| &o.tag.0 == tag) {
+            if !replaced.contains(tag) {
+                replaced.push(tag.clone());
+                for (o, c) in overrides.iter().filter(|(o, _)| &o.tag.0 == tag) {
+                    spec.rules.push(o.clone());
+                    columns.push(Some(*c));
+                }
+            }
+        } else if !removals.iter().any(|(t, _, _)| t == tag) {
+            spec.rules.push(rule);
+            columns.push(column);
+        }
+    }
+    Ok(())
+}
+
+// returns the spec and where the pattern of every rule starts in its line,
+// None for included rules
+fn parse_arrows(
+    content: &str,
+    dir: &Path,
+    flags: &[String],
+    includes: &mut Includes,
+) -> Result<(LexSpec, Vec<Option<usize>>), SpecError> {
+    let mut spec = LexSpec::default();
+    let mut columns = Vec::new();
+    let mut overrides = Vec::new();
+    let mut removals = Vec::new();
+    for (no, line) in content.lines().enumerate() {
+        let no = no + 1;
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with("//") {
+            continue;
+        }
+        if let Some(included) = parse_include(line, no, dir, flags, includes)? {
+            merge(&mut spec, &mut columns, included);
+            continue;
+        }
+        if let Some(tags) = trimmed.strip_prefix("%remove ") {
+            let mut end = 0;
+            for tag in tags.split_whitespace() {
+                let column = end + line[end..].find(tag).unwrap();
+                end = column + tag.len();
+                removals.push((tag.to_string(), no, column));
+            }
+            continue;
+        }
+        if let Some(rules) = parse_bulk(line, no)? {
+            for (rule, column) in rules {
+                spec.rules.push(rule);
+                columns.push(Some(column));
+            }
+            continue;
+        }
+        // the name ends at the first `->`, everything after it is the pattern,
+        // so `PTR_OP->->` works, unless the name is followed by something other
+        // than attributes, then the line is a definition like `ARROW "->"`
+        let rule = line
+            .split_once("->")
+            .filter(|(head, _)| head.split_whitespace().skip(1).all(|w| w.starts_with('%')));
+        let Some((mut head, pattern_str)) = rule else {
+            if parse_declaration(line, &mut spec) {
+                continue;
+            }
+            match parse_definition(line, no) {
+                Some(definition) => spec.definitions.push(definition),
+                None => {
+                    return error(
+                        no,
+                        0,
+                        "expected `NAME->regex`, `NAME regex` or a `%s`/`%x` declaration",
+                    )
+                }
+            }
+            continue;
+        };
+        let column = head.len() + 2;
+        let pattern_str = trim_pattern(pattern_str);
+        // `<A,B>TAG` limits the rule to the given modes, a lone `<` is still a tag
+        let mut start_conditions = Vec::new();
+        if let Some((modes, rest)) = head.strip_prefix('<').and_then(|h| h.split_once('>')) {
+            if !rest.trim().is_empty() {
+                start_conditions = modes.split(',').map(|m| m.trim().to_string()).collect();
+                head = rest;
+            }
+        }
+        // the tag may be followed by attributes, e.g. `SELECT %i->select`
+        let mut head = head.split_whitespace();
+        let Some(tag_str) = head.next() else {
+            return error(no, 0, "missing rule name before `->`");
+        };
+        let mut rule = Rule::new(pattern_str.to_string(), Tag(tag_str.to_string()));
+        rule.start_conditions = start_conditions;
+        rule.line = Some(no);
+        let mut is_override = false;
+        for attribute in head {
+            // `%begin(MODE)`, `%push(MODE)` and `%pop` switch modes after a match
+            let (name, mode) = match attribute.strip_suffix(')').and_then(|a| a.split_once('(')) {
+                Some((name, mode)) => (name, Some(mode.to_string())),
+                None => (attribute, None),
+            };
+            match (name, mode) {
+                ("%i", None) => rule.case_insensitive = true,
+                ("%override", None) => is_override = true,
+                ("%pop", None) => rule.mode_action = Some(ModeAction::Pop),
+                ("%prio", Some(priority)) => match priority.parse() {
+                    Ok(priority) => rule.priority = Some(priority),
+                    Err(_) => {
+                        let column = line.find(attribute).unwrap_or(0) + "%prio(".len();
+                        return error(no, column, format!("invalid priority {}", priority));
+                    }
+                },
+                ("%begin", Some(mode)) => rule.mode_action = Some(ModeAction::Begin(mode)),
+                ("%push", Some(mode)) => rule.mode_action = Some(ModeAction::Push(mode)),
+                _ => {
+                    let column = line.find(attribute).unwrap_or(0);
+                    return error(no, column, format!("unknown attribute {}", attribute));
+                }
+            }
+        }
+        if is_override {
+            overrides.push((rule, column));
+        } else {
+            spec.rules.push(rule);
+            columns.push(Some(column));
+        }
+    }
+    apply_overrides(&mut spec, &mut columns, overrides, removals)?;
+    Ok((spec, columns))
+}
+
+const ARROW_DIRECTIVES: &[&str] = &["%include", "%override", "%remove"];
+
+fn parse_flex(content: &str) -> Result<(LexSpec, Vec<Option<usize>>), SpecError> {
+    let mut spec = LexSpec::default();
+    let mut columns = Vec::new();
+    let mut lines = content.lines().enumerate();
+    while let Some((no, line)) = lines.next() {
+        if line.trim_end() == "%%" {
+            break;
+        }
+        if let Some(code) = read_code(line, &mut lines) {
+            spec.code.push(code);
+        } else if line.trim().is_empty() || parse_declaration(line, &mut spec) {
+            continue;
+        } else if line.starts_with('%') {
+            check_flex_directive(line, no + 1)?;
+        } else if let Some(definition) = parse_definition(line, no + 1) {
+            spec.definitions.push(definition);
+        } else {
+            return error(no + 1, 0, "expected a definition like `NAME regex`");
+        }
+    }
+    // rules whose action is `|`, they share the action of the next rule
+    let mut pending: Vec<Rule> = Vec::new();
+    while let Some((no, line)) = lines.next() {
+        let no = no + 1;
+        if line.trim_end() == "%%" {
+            break;
+        }
+        if let Some(code) = read_code(line, &mut lines) {
+            spec.code.push(code);
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        if ARROW_DIRECTIVES.contains(&line.split_whitespace().next().unwrap()) {
+            check_flex_directive(line, no)?;
+        }
+        if let Some(rules) = parse_bulk(line, no)? {
+            if !pending.is_empty() {
+                return error(no, 0, "a rule with '|' as its action needs a rule after it");
+            }
+            for (rule, column) in rules {
+                spec.rules.push(rule);
+                columns.push(Some(column));
+            }
+            continue;
+        }
+        let (start_conditions, rest) = split_start_conditions(line);
+        let pattern_column = line.len() - rest.len();
+        let (pattern, rest) = split_pattern(rest);
+        if pattern == "<<EOF>>" {
+            return error(no, pattern_column, "<<EOF>> rules are not supported");
+        }
+
+        let mut action = rest.trim().to_string();
+        while brace_depth(&action) > 0 {
+            match lines.next() {
+                Some((_, line)) => {
+                    action.push('\n');
+                    action.push_str(line);
+                }
+                None => return error(no, line.len() - rest.len(), "unterminated action"),
+            }
+        }
+        let mut rule = Rule::new(pattern.to_string(), Tag(String::new()));
+        rule.start_conditions = start_conditions;
+        rule.line = Some(no);
+        columns.push(Some(pattern_column));
+        if action == "|" {
+            pending.push(rule);
+            continue;
+        }
+        apply_action(&mut rule, &action);
+        for mut other in pending.drain(..) {
+            other.tag = rule.tag.clone();
+            other.mode_action = rule.mode_action.clone();
+            other.action = rule.action.clone();
+            spec.rules.push(other);
+        }
+        spec.rules.push(rule);
+    }
+    if let Some(rule) = pending.first() {
+        return error(
+            rule.line.unwrap_or(0),
+            0,
+            "the last rule can't have '|' as its action",
+        );
+    }
+    spec.user_code = lines.map(|(_, line)| line).collect::<Vec<_>>().join("\n");
+    Ok((spec, columns))
+}
+
+// `%option` and the like don't change how rules are matched, the directives
+// of `->` files are errors rather than being skipped
+fn check_flex_directive(line: &str, no: usize) -> Result<(), SpecError> {
+    let directive = line.split_whitespace().next().unwrap_or(line);
+    if ARROW_DIRECTIVES.contains(&directive) {
+        let message = format!("{} only works in `->` rule files", directive);
+        return error(no, 0, message);
+    }
+    if !["%option", "%pointer", "%array"].contains(&directive) {
+        return error(no, 0, format!("unknown directive {}", directive));
+    }
+    Ok(())
+}
+
+// a `%{ %}` block, a `/* */` comment or an indented line is copied as it is
+fn read_code(line: &str, lines: &mut LineIter) -> Option<String> {
+    if line.trim_end() == "%{" {
+        let code = lines
+            .map(|(_, line)| line)
+            .take_while(|line| line.trim_end() != "%}")
+            .collect::<Vec<_>>();
+        Some(code.join("\n"))
+    } else if line.starts_with("/*") {
+        let mut code = vec![line];
+        if !line.contains("*/") {
+            for (_, line) in lines.by_ref() {
+                code.push(line);
+                if line.contains("*/") {
+                    break;
+                }
+            }
+        }
+        Some(code.join("\n"))
+    } else if line.starts_with(char::is_whitespace) && !line.trim().is_empty() {
+        Some(line.to_string())
+    } else {
+        None
+    }
+}
+
+// `<A,B>pattern` limits the rule to the given modes
+fn split_start_conditions(line: &str) -> (Vec<String>, &str) {
+    if let Some((modes, rest)) = line.strip_prefix('<').and_then(|l| l.split_once('>')) {
+        let is_mode = |c: char| c.is_alphanumeric() || matches!(c, '_' | ',' | '*');
+        if !modes.is_empty() && modes.chars().all(is_mode) {
+            let modes = modes.split(',').map(str::to_string).collect();
+            return (modes, rest);
+        }
+    }
+    (Vec::new(), line)
+}
+
+// the pattern ends at the first whitespace outside of quotes and brackets
+fn split_pattern(line: &str) -> (&str, &str) {
+    let mut in_quote = false;
+    let mut in_class = false;
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' if !in_class => in_quote = !in_quote,
+            '[' if !in_quote => in_class = true,
+            ']' if !in_quote => in_class = false,
+            c if c.is_whitespace() && !in_quote && !in_class => return line.split_at(i),
+            _ => {}
+        }
+    }
+    (line, "")
+}
+
+// the number of braces left open in C code, ignoring literals and comments
+fn brace_depth(code: &str) -> i32 {
+    let mut depth = 0;
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '"' | '\'' => {
+                while let Some(d) = chars.next() {
+                    match d {
+                        '\\' => {
+                            chars.next();
+                        }
+                        d if d == c => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                chars.find(|&d| d == '\n');
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for d in chars.by_ref() {
+                    if last == '*' && d == '/' {
+                        break;
+                    }
+                    last = d;
+                }
+            }
+            _ => {}
+        }
+    }
+    depth
+}
+
+// splits C code into statements on the `;` and braces outside of literals
+fn statements(code: &str) -> Vec<String> {
+    let mut statements = vec![String::new()];
+    let mut chars = code.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ';' | '{' | '}' => statements.push(String::new()),
+            '"' | '\'' => {
+                let current = statements.last_mut().unwrap();
+                current.push(c);
+                while let Some(d) = chars.next() {
+                    current.push(d);
+                    match d {
+                        '\\' => current.extend(chars.next()),
+                        d if d == c => break,
+                        _ => {}
+                    }
+                }
+            }
+            c => statements.last_mut().unwrap().push(c),
+        }
+    }
+    statements
+        .into_iter()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+// `X`, `(X)` or `( X )`
+fn argument(code: &str) -> &str {
+    let code = code.trim();
+    code.strip_prefix('(')
+        .and_then(|c| c.strip_suffix(')'))
+        .unwrap_or(code)
+        .trim()
+}
+
+// the token of `return IDENTIFIER;` or `return 'c';`
+fn return_tag(value: &str) -> Option<Tag> {
+    let value = argument(value);
+    if !value.is_empty() && value.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Some(Tag(value.to_string()));
+    }
+    let c = match value.strip_prefix('\'')?.strip_suffix('\'')? {
+        "\\n" => '\n',
+        "\\t" => '\t',
+        "\\\\" => '\\',
+        "\\'" => '\'',
+        c if c.chars().count() == 1 => c.chars().next().unwrap(),
+        _ => return None,
+    };
+    Some(Tag(c.to_string()))
+}
+
+// keeps the action and reads the returned token and mode switches from it
+fn apply_action(rule: &mut Rule, action: &str) {
+    for statement in statements(action) {
+        let word_end = statement
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(statement.len());
+        let (word, rest) = statement.split_at(word_end);
+        match word {
+            "return" => {
+                if let Some(tag) = return_tag(rest) {
+                    rule.tag = tag;
+                }
+            }
+            "BEGIN" => {
+                let mode = match argument(rest) {
+                    "0" => INITIAL,
+                    mode => mode,
+                };
+                rule.mode_action = Some(ModeAction::Begin(mode.to_string()));
+            }
+            "yy_push_state" => {
+                rule.mode_action = Some(ModeAction::Push(argument(rest).to_string()));
+            }
+            "yy_pop_state" => rule.mode_action = Some(ModeAction::Pop),
+            _ => {}
+        }
+    }
+    if !matches!(action, "" | ";") {
+        rule.action = Some(action.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_arrows() {
+        let spec = parse("%x STRING\nSELECT %i->select\n<STRING>END %pop->\\\"\n<-><\n").unwrap();
+        assert_eq!(spec.start_conditions.len(), 1);
+        assert!(spec.rules[0].case_insensitive);
+        assert_eq!(spec.rules[1].start_conditions, ["STRING"]);
+        assert_eq!(spec.rules[1].mode_action, Some(ModeAction::Pop));
+        assert_eq!(spec.rules[2].tag, Tag("<".to_string()));
+        assert_eq!(spec.rules[2].pattern, "<");
+
+        let spec = parse("IF %prio(2) %i->if\nELSE %prio(-1)->else\nID->[a-z]+\n").unwrap();
+        let priorities = spec
+            .rules
+            .iter()
+            .map(|rule| rule.priority)
+            .collect::<Vec<_>>();
+        assert_eq!(priorities, [Some(2), Some(-1), None]);
+        assert!(spec.rules[0].case_insensitive);
+        let e = parse("A->a\nIF %prio(x)->if\n").unwrap_err();
+        assert_eq!(
+            (e.line, e.column, e.message.as_str()),
+            (2, 9, "invalid priority x")
+        );
+    }
+
+    #[test]
+    fn test_parse_flex() {
+        let spec = parse(
+            r#"%{
+#include "y.tab.h"
+%}
+D			[0-9]
+%x COMMENT
+%option noyywrap
+
+%%
+"/*"			{ BEGIN(COMMENT); }
+<COMMENT>"*/"		{ BEGIN(INITIAL); }
+<COMMENT>.|\n		;
+"auto"			{ count(); return(AUTO); }
+[0-9]+			{
+				yylval = atoi(yytext);
+				return CONSTANT;
+			}
+"{"|"<%"		{ count(); return('{'); }
+"'"			|
+"\""			{ return QUOTE; /* } */ }
+[ \t\n]			{ count(); }
+%%
+int yywrap() { return 1; }
+"#,
+        )
+        .unwrap();
+        assert_eq!(spec.code, ["#include \"y.tab.h\""]);
+        assert_eq!(
+            spec.definitions,
+            [Definition {
+                name: "D".to_string(),
+                pattern: "[0-9]".to_string(),
+                line: Some(4),
+            }]
+        );
+        assert_eq!(
+            spec.start_conditions,
+            [StartCondition {
+                name: "COMMENT".to_string(),
+                exclusive: true,
+            }]
+        );
+        let rules = spec
+            .rules
+            .iter()
+            .map(|rule| (rule.pattern.as_str(), rule.tag.0.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rules,
+            [
+                ("\"/*\"", ""),
+                ("\"*/\"", ""),
+                (".|\\n", ""),
+                ("\"auto\"", "AUTO"),
+                ("[0-9]+", "CONSTANT"),
+                ("\"{\"|\"<%\"", "{"),
+                ("\"'\"", "QUOTE"),
+                ("\"\\\"\"", "QUOTE"),
+                ("[ \\t\\n]", ""),
+            ]
+        );
+        assert_eq!(
+            spec.rules[0].mode_action,
+            Some(ModeAction::Begin("COMMENT".to_string()))
+        );
+        assert_eq!(
+            spec.rules[1].mode_action,
+            Some(ModeAction::Begin("INITIAL".to_string()))
+        );
+        assert_eq!(spec.rules[1].start_conditions, ["COMMENT"]);
+        assert_eq!(spec.rules[2].action, None);
+        assert!(spec.rules[4]
+            .action
+            .as_ref()
+            .unwrap()
+            .contains("yylval = atoi(yytext);"));
+        assert_eq!(spec.rules[8].action.as_deref(), Some("{ count(); }"));
+        assert_eq!(spec.user_code, "int yywrap() { return 1; }");
+
+        let e = parse("%include \"base.l\"\n%%\nx ;\n").unwrap_err();
+        assert_eq!(
+            (e.line, e.column, e.message.as_str()),
+            (1, 0, "%include only works in `->` rule files")
+        );
+        let e = parse("%%\nx ;\n%remove X\n").unwrap_err();
+        assert_eq!((e.line, e.column), (3, 0));
+        let e = parse("%pointer\n%yylineno\n%%\nx ;\n").unwrap_err();
+        assert_eq!(
+            (e.line, e.message.as_str()),
+            (2, "unknown directive %yylineno")
+        );
+    }
+
+    #[test]
+    fn test_parse_arrows_syntax() {
+        let spec = parse(
+            "# comment\n  // another one\nPTR_OP->->\nARROW->\"->\"\nSPACE->\\ \t \nWORD->[a-z]+  \n",
+        )
+        .unwrap();
+        let patterns = spec
+            .rules
+            .iter()
+            .map(|rule| (rule.pattern.as_str(), rule.line.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            patterns,
+            [("->", 3), ("\"->\"", 4), ("\\ ", 5), ("[a-z]+", 6)]
+        );
+    }
+
+    #[test]
+    fn test_parse_definitions() {
+        let spec = parse("D [0-9]\nARROW \"->\"\nNUM->{D}+\nNUM_ARROW->{D}{ARROW}\n").unwrap();
+        assert_eq!(spec.definitions.len(), 2);
+        assert_eq!(spec.definitions[1].pattern, "\"->\"");
+        assert_eq!(spec.rules.len(), 2);
+        let e = parse("D [0-9]\nNUM->{D}+{E}\n").unwrap_err();
+        assert_eq!(
+            (e.line, e.column, e.message.as_str()),
+            (2, 9, "undefined definition {E}")
+        );
+        let e = parse("A {B}\nB x{A}\nX->{A}\n").unwrap_err();
+        assert_eq!(
+            (e.line, e.message.as_str()),
+            (1, "in {A}: in {B}: {A} refers to itself")
+        );
+        let e = parse("D [0-9]\nX->x\nD [a-f]\n").unwrap_err();
+        assert_eq!(
+            (e.line, e.message.as_str()),
+            (3, "{D} is already defined on line 1")
+        );
+        let e = parse("D [0-9]\nD [a-f]\n%%\n{D} x\n").unwrap_err();
+        assert_eq!(e.line, 2);
+    }
+
+    #[test]
+    fn test_parse_bulk() {
+        let spec =
+            parse("A->a\n%keywords while _Bool=BOOL\n%literals ; -> \" \\\nID->[a-z]+\n").unwrap();
+        let rules = spec
+            .rules
+            .iter()
+            .map(|rule| (rule.pattern.as_str(), rule.tag.0.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rules,
+            [
+                ("a", "A"),
+                ("\"while\"", "WHILE"),
+                ("\"_Bool\"", "BOOL"),
+                ("\";\"", ";"),
+                ("\"->\"", "->"),
+                ("\"\\\"\"", "\""),
+                ("\"\\\\\"", "\\"),
+                ("[a-z]+", "ID"),
+            ]
+        );
+        let e = parse("%keywords if a+b\n").unwrap_err();
+        assert_eq!(
+            (e.line, e.column, e.message.as_str()),
+            (1, 13, "invalid keyword a+b")
+        );
+    }
+
+    #[test]
+    fn test_conditional_lines() {
+        let content = "A->a\n%if C11\nB->b\n%if !GNU\nC->c\n%else\nD->d\n%endif\n%endif\nE->e\n";
+        let tags = |flags: &[&str]| {
+            let flags = flags.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+            parse_with_flags(content, &flags)
+                .unwrap()
+                .rules
+                .iter()
+                .map(|rule| (rule.tag.0.clone(), rule.line.unwrap()))
+                .collect::<Vec<_>>()
+        };
+        let expected = |rules: &[(&str, usize)]| {
+            rules
+                .iter()
+                .map(|&(tag, line)| (tag.to_string(), line))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tags(&[]), expected(&[("A", 1), ("E", 10)]));
+        assert_eq!(
+            tags(&["C11"]),
+            expected(&[("A", 1), ("B", 3), ("C", 5), ("E", 10)])
+        );
+        assert_eq!(
+            tags(&["C11", "GNU"]),
+            expected(&[("A", 1), ("B", 3), ("D", 7), ("E", 10)])
+        );
+        // the flex layout is selected the same way
+        let flex =
+            "%%\n%if C11\n\"_Atomic\"  { return ATOMIC; }\n%endif\n[a-z_A-Z]+  { return ID; }\n";
+        assert_eq!(parse(flex).unwrap().rules.len(), 1);
+        let flags = ["C11".to_string()];
+        assert_eq!(parse_with_flags(flex, &flags).unwrap().rules.len(), 2);
+
+        let err = |content: &str| {
+            let e = parse(content).unwrap_err();
+            (e.line, e.column, e.message)
+        };
+        assert_eq!(
+            err("A->a\n%if C11\nB->b\n"),
+            (2, 0, "%if without %endif".to_string())
+        );
+        assert_eq!(
+            err("A->a\n%endif\n"),
+            (2, 0, "%endif without %if".to_string())
+        );
+        assert_eq!(
+            err("%if A\n%else\n%else\n%endif\n").2,
+            "second %else for the same %if".to_string()
+        );
+        assert_eq!(
+            err("%if 1C\n%endif\n"),
+            (1, 4, "invalid flag 1C".to_string())
+        );
+        assert_eq!(err("%if\n%endif\n").0, 1);
+        // errors in the kept lines point at their own line
+        assert_eq!(err("%if !X\nA->a\n%endif\nB->(\n").0, 4);
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("seu_lex_include_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("base")).unwrap();
+        let write = |name: &str, content: &str| std::fs::write(dir.join(name), content).unwrap();
+        write(
+            "base/base.l",
+            "%x S\nA->a\nB->b\nID->[a-z]+\nC->c\nB->bbb\n",
+        );
+        write(
+            "dialect.l",
+            "NEW->new\n%include \"base/base.l\"\nB %override->bb\n%remove C\nLAST->z\n",
+        );
+        let read = |name: &str| read_from_lex_file(dir.join(name).to_str().unwrap());
+        let spec = read("dialect.l").unwrap();
+        let rules = spec
+            .rules
+            .iter()
+            .map(|rule| {
+                (
+                    rule.tag.0.as_str(),
+                    rule.pattern.as_str(),
+                    rule.line.unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rules,
+            [
+                ("NEW", "new", 1),
+                ("A", "a", 2),
+                ("B", "bb", 3),
+                ("ID", "[a-z]+", 4),
+                ("LAST", "z", 5),
+            ]
+        );
+        assert_eq!(spec.start_conditions.len(), 1);
+
+        write("x.l", "%include \"y.l\"\n");
+        write("y.l", "A->a\n%include \"x.l\"\n");
+        let e = read("x.l").unwrap_err();
+        assert_eq!(e.path, Some(dir.join("y.l").display().to_string()));
+        assert_eq!(e.line, 2);
+        let chain = ["x.l", "y.l", "x.l"].map(|name| dir.join(name).display().to_string());
+        assert_eq!(e.message, format!("include cycle: {}", chain.join(" -> ")));
+
+        write("bad.l", "%include \"base/base.l\"\nD %override->d\n");
+        assert_eq!(
+            read("bad.l").unwrap_err().message,
+            "no included rule D to override"
+        );
+        write("bad.l", "A->a\n%remove A\n");
+        assert_eq!(
+            read("bad.l").unwrap_err().message,
+            "no included rule A to remove"
+        );
+        write("bad.l", "%include \"missing.l\"\n");
+        assert_eq!(read("bad.l").unwrap_err().line, 1);
+        write("base/broken.l", "A->a\nB->(\n");
+        write("bad.l", "%include \"base/broken.l\"\n");
+        let e = read("bad.l").unwrap_err();
+        assert_eq!(
+            e.path,
+            Some(dir.join("base/broken.l").display().to_string())
+        );
+        assert_eq!(e.line, 2);
+        write("base/digits.l", "D [0-9]\nNUM->{D}+\n");
+        write("bad.l", "X->x\n%include \"base/digits.l\"\nD [a-f]\n");
+        let e = read("bad.l").unwrap_err();
+        assert_eq!(
+            (e.line, e.message.as_str()),
+            (3, "{D} is also defined in an included file")
+        );
+
+        // flags reach the included files
+        write("base/flags.l", "%if X\nX->x\n%endif\n");
+        write("flags.l", "%include \"base/flags.l\"\n");
+        let path = dir.join("flags.l");
+        let flags = ["X".to_string()];
+        assert_eq!(read("flags.l").unwrap().rules.len(), 0);
+        let spec = read_with_flags(path.to_str().unwrap(), &flags).unwrap();
+        assert_eq!(spec.rules.len(), 1);
+
+        // a file reached through two includes is an error at the second one
+        write("left.l", "%include \"base/digits.l\"\nL->l\n");
+        write("right.l", "R->r\n%include \"base/digits.l\"\n");
+        write("diamond.l", "%include \"left.l\"\n%include \"right.l\"\n");
+        let e = read("diamond.l").unwrap_err();
+        assert_eq!(e.path, Some(dir.join("right.l").display().to_string()));
+        assert_eq!((e.line, e.column), (2, 9));
+        let digits = dir.join("base/digits.l").display().to_string();
+        assert_eq!(e.message, format!("{} is already included", digits));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |content: &str| {
+            let e = parse(content).unwrap_err();
+            (e.line, e.column, e.message)
+        };
+        assert_eq!(
+            err("A->a\nB\n"),
+            (
+                2,
+                0,
+                "expected `NAME->regex`, `NAME regex` or a `%s`/`%x` declaration".to_string()
+            )
+        );
+        assert_eq!(err("A->a\n->b\n").0, 2);
+        assert_eq!(err("A->a\nB->(b\n").1, 5);
+        assert_eq!(
+            err("A->a\nB %j->b\n"),
+            (2, 2, "unknown attribute %j".to_string())
+        );
+        assert_eq!(
+            err("<S>A->a\n"),
+            (1, 0, "undeclared start condition S".to_string())
+        );
+        assert_eq!(err("%%\n\"a\"   |\n%%\n").0, 2);
+        assert_eq!(err("%%\n<<EOF>>  { return 0; }\n").0, 2);
+        assert_eq!(err("%%\na  { return A;\n").0, 2);
+        assert_eq!(err("%%\na  { return A; }\n[b  { return B; }\n").0, 3);
+        let e = parse("A->a\nB->(b\n").unwrap_err();
+        assert_eq!(e.to_string(), "line 2: expected ')', found end of pattern");
+    }
+}